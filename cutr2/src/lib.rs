@@ -1,14 +1,17 @@
 use crate::Extract::*;
 use clap::{App, Arg};
-use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use csv::{QuoteStyle, ReaderBuilder, StringRecord, WriterBuilder};
 use regex::Regex;
+use serde_json::{Map, Value};
 use std::{
     error::Error,
     fs::File,
-    io::{self, BufRead, BufReader},
+    io::{self, BufRead, BufReader, Write},
     num::NonZeroUsize,
     ops::Range,
 };
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 type PositionList = Vec<Range<usize>>;
@@ -16,15 +19,64 @@ type PositionList = Vec<Range<usize>>;
 #[derive(Debug)]
 pub enum Extract {
     Fields(PositionList),
+    Names(Vec<String>),
     Bytes(PositionList),
     Chars(PositionList),
+    Columns(PositionList),
+    Layout(Vec<LayoutField>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    Text,
+    Integer,
+    Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutField {
+    name: String,
+    pos: Range<usize>,
+    kind: FieldType,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Tsv,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum Delimiter {
+    Byte(u8),
+    Text(String),
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+pub struct Dialect {
+    quote: u8,
+    escape: Option<u8>,
+    double_quote: bool,
+    comment: Option<u8>,
+    flexible: bool,
+    quote_style: QuoteStyle,
 }
 
 #[derive(Debug)]
 pub struct Config {
     files: Vec<String>,
-    delimiter: u8,
+    delimiter: Delimiter,
+    output_delimiter: String,
+    dialect: Dialect,
     extract: Extract,
+    complement: bool,
+    only_delimited: bool,
+    graphemes: bool,
+    no_split: bool,
+    char_units: bool,
+    format: OutputFormat,
 }
 
 pub fn get_args() -> MyResult<Config> {
@@ -47,61 +99,321 @@ pub fn get_args() -> MyResult<Config> {
                 .help("Field delimiter")
                 .default_value("\t"),
         )
+        .arg(
+            Arg::with_name("regex_delimiter")
+                .value_name("PATTERN")
+                .long("regex-delimiter")
+                .help("Field delimiter regular expression"),
+        )
         .arg(
             Arg::with_name("fields")
                 .value_name("FIELDS")
                 .short("f")
                 .long("fields")
+                .allow_hyphen_values(true)
                 .help("Selected fields")
-                .conflicts_with_all(&["chars", "bytes"]),
+                .conflicts_with_all(&["chars", "bytes", "names", "columns"]),
+        )
+        .arg(
+            Arg::with_name("names")
+                .value_name("NAMES")
+                .short("F")
+                .long("names")
+                .help("Selected fields by header name, glob, or /regex/")
+                .conflicts_with_all(&["fields", "chars", "bytes", "columns"]),
         )
         .arg(
             Arg::with_name("bytes")
                 .value_name("BYTES")
                 .short("b")
                 .long("bytes")
+                .allow_hyphen_values(true)
                 .help("Selected bytes")
-                .conflicts_with_all(&["fields", "chars"]),
+                .conflicts_with_all(&["fields", "names", "chars", "columns"]),
         )
         .arg(
             Arg::with_name("chars")
                 .value_name("CHARS")
                 .short("c")
                 .long("chars")
+                .allow_hyphen_values(true)
                 .help("Selected characters")
-                .conflicts_with_all(&["fields", "bytes"]),
+                .conflicts_with_all(&["fields", "names", "bytes", "columns"]),
+        )
+        .arg(
+            Arg::with_name("no_split")
+                .value_name("NO_SPLIT")
+                .short("n")
+                .help("Do not split multibyte characters with --bytes")
+                .takes_value(false)
+                .requires("bytes"),
+        )
+        .arg(
+            Arg::with_name("layout")
+                .value_name("LAYOUT")
+                .long("layout")
+                .help("Fixed-width layout file of name,start,width[,type]")
+                .conflicts_with_all(&["fields", "bytes", "chars", "columns"]),
+        )
+        .arg(
+            Arg::with_name("units")
+                .value_name("UNITS")
+                .long("units")
+                .help("Units for --layout offsets")
+                .possible_values(&["bytes", "chars"])
+                .default_value("bytes"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .value_name("FORMAT")
+                .long("format")
+                .help("Output format for --layout")
+                .possible_values(&["csv", "tsv", "json"])
+                .default_value("csv"),
+        )
+        .arg(
+            Arg::with_name("graphemes")
+                .value_name("GRAPHEMES")
+                .short("g")
+                .long("graphemes")
+                .help("Count --chars as grapheme clusters")
+                .takes_value(false)
+                .requires("chars"),
+        )
+        .arg(
+            Arg::with_name("columns")
+                .value_name("COLUMNS")
+                .long("columns")
+                .allow_hyphen_values(true)
+                .help("Selected display columns")
+                .conflicts_with_all(&["fields", "names", "bytes", "chars"]),
+        )
+        .arg(
+            Arg::with_name("complement")
+                .value_name("COMPLEMENT")
+                .long("complement")
+                .help("Complement the selected fields, bytes, or characters")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("keep_order")
+                .value_name("KEEP_ORDER")
+                .long("keep-order")
+                .help("Output -f, -b and -c in the order given instead of input order")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("output_delimiter")
+                .value_name("DELIMITER")
+                .long("output-delimiter")
+                .help("Output field delimiter [default: input delimiter, or tab for --regex-delimiter]"),
+        )
+        .arg(
+            Arg::with_name("quote")
+                .value_name("CHAR")
+                .long("quote")
+                .help("CSV quote character")
+                .default_value("\""),
+        )
+        .arg(
+            Arg::with_name("escape")
+                .value_name("CHAR")
+                .long("escape")
+                .help("CSV escape character for quotes, e.g. \\"),
+        )
+        .arg(
+            Arg::with_name("no_double_quote")
+                .value_name("NO_DOUBLE_QUOTE")
+                .long("no-double-quote")
+                .help("Do not treat doubled quotes as an escaped quote")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("comment")
+                .value_name("CHAR")
+                .long("comment")
+                .help("Skip CSV lines starting with this character"),
+        )
+        .arg(
+            Arg::with_name("flexible")
+                .value_name("FLEXIBLE")
+                .long("flexible")
+                .help("Allow CSV rows with differing numbers of fields")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("quote_style")
+                .value_name("STYLE")
+                .long("quote-style")
+                .help("When to quote CSV output fields")
+                .possible_values(&["always", "necessary", "never", "non-numeric"])
+                .default_value("necessary"),
+        )
+        .arg(
+            Arg::with_name("only_delimited")
+                .value_name("ONLY_DELIMITED")
+                .short("s")
+                .long("only-delimited")
+                .help("Do not print lines without delimiters")
+                .takes_value(false),
         )
         .get_matches();
 
-    let delimiter = matches.value_of("delimiter").unwrap();
-    let delim_bytes = delimiter.as_bytes();
-    if delim_bytes.len() != 1 {
-        return Err(From::from(format!(
-            "--delim \"{}\" must be a single byte",
-            delimiter
-        )));
-    }
+    let delimiter = match matches.value_of("regex_delimiter") {
+        Some(_) if matches.occurrences_of("delimiter") > 0 => {
+            return Err(From::from("--delim cannot be used with --regex-delimiter"));
+        }
+        Some(pattern) => Delimiter::Pattern(
+            Regex::new(pattern)
+                .map_err(|_| format!("Invalid --regex-delimiter \"{}\"", pattern))?,
+        ),
+        None => parse_delimiter(matches.value_of("delimiter").unwrap())?,
+    };
+    let output_delimiter = match (matches.value_of("output_delimiter"), &delimiter) {
+        (Some(delim), _) => delim.to_string(),
+        (None, Delimiter::Byte(byte)) => char::from(*byte).to_string(),
+        (None, Delimiter::Text(text)) => text.to_string(),
+        (None, Delimiter::Pattern(_)) => "\t".to_string(),
+    };
+
+    let dialect = Dialect {
+        quote: parse_byte("--quote", matches.value_of("quote").unwrap())?,
+        escape: matches
+            .value_of("escape")
+            .map(|escape| parse_byte("--escape", escape))
+            .transpose()?,
+        double_quote: !matches.is_present("no_double_quote"),
+        comment: matches
+            .value_of("comment")
+            .map(|comment| parse_byte("--comment", comment))
+            .transpose()?,
+        flexible: matches.is_present("flexible"),
+        quote_style: match matches.value_of("quote_style") {
+            Some("always") => QuoteStyle::Always,
+            Some("never") => QuoteStyle::Never,
+            Some("non-numeric") => QuoteStyle::NonNumeric,
+            _ => QuoteStyle::Necessary,
+        },
+    };
 
     let fields = matches.value_of("fields").map(parse_pos).transpose()?;
     let bytes = matches.value_of("bytes").map(parse_pos).transpose()?;
     let chars = matches.value_of("chars").map(parse_pos).transpose()?;
+    let columns = matches.value_of("columns").map(parse_pos).transpose()?;
+
+    let names = matches.value_of("names").map(parse_names).transpose()?;
+    let keep_order = matches.is_present("keep_order");
+    let (fields, bytes, chars, columns) = if keep_order {
+        (fields, bytes, chars, columns)
+    } else {
+        (
+            fields.map(sort_pos),
+            bytes.map(sort_pos),
+            chars.map(sort_pos),
+            columns.map(sort_pos),
+        )
+    };
 
-    let extract = if let Some(field_pos) = fields {
+    let extract = if let Some(layout_file) = matches.value_of("layout") {
+        let layout = read_layout(layout_file)?;
+        match names {
+            Some(names) => Layout(select_layout(layout, &names)?),
+            None => Layout(layout),
+        }
+    } else if let Some(field_pos) = fields {
         Fields(field_pos)
+    } else if let Some(names) = names {
+        Names(names)
     } else if let Some(byte_pos) = bytes {
         Bytes(byte_pos)
     } else if let Some(char_pos) = chars {
         Chars(char_pos)
+    } else if let Some(col_pos) = columns {
+        Columns(col_pos)
     } else {
-        return Err(From::from("Must have --fields, --bytes, or --chars"));
+        return Err(From::from(
+            "Must have --fields, --names, --bytes, --chars, --columns, or --layout",
+        ));
     };
+
+    let field_mode = matches!(extract, Fields(_) | Names(_));
+    if !field_mode && matches.is_present("output_delimiter") {
+        return Err(From::from(
+            "--output-delimiter requires --fields or --names",
+        ));
+    }
+    if !field_mode && matches.is_present("only_delimited") {
+        return Err(From::from("--only-delimited requires --fields or --names"));
+    }
+    // Only the CSV reader and writer know about quoting and comments
+    let csv_mode = field_mode && matches!(delimiter, Delimiter::Byte(_));
+    for opt in [
+        "quote",
+        "escape",
+        "no-double-quote",
+        "comment",
+        "flexible",
+        "quote-style",
+    ] {
+        if !csv_mode && matches.occurrences_of(opt.replace('-', "_")) > 0 {
+            return Err(From::from(format!(
+                "--{} requires --fields or --names with a one-byte --delim",
+                opt
+            )));
+        }
+    }
+    if output_delimiter.len() > 1 && matches.occurrences_of("quote_style") > 0 {
+        return Err(From::from(
+            "--quote-style requires a one-byte --output-delimiter",
+        ));
+    }
+    let layout_mode = matches!(extract, Layout(_));
+    if layout_mode && matches.is_present("complement") {
+        return Err(From::from("--complement cannot be used with --layout"));
+    }
+    for opt in ["units", "format"] {
+        if !layout_mode && matches.occurrences_of(opt) > 0 {
+            return Err(From::from(format!("--{} requires --layout", opt)));
+        }
+    }
+
     Ok(Config {
         files: matches.values_of_lossy("files").unwrap(),
-        delimiter: *delim_bytes.first().unwrap(),
+        delimiter,
+        output_delimiter,
+        dialect,
         extract,
+        complement: matches.is_present("complement"),
+        only_delimited: matches.is_present("only_delimited"),
+        graphemes: matches.is_present("graphemes"),
+        no_split: matches.is_present("no_split"),
+        char_units: matches.value_of("units") == Some("chars"),
+        format: match matches.value_of("format") {
+            Some("tsv") => OutputFormat::Tsv,
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Csv,
+        },
     })
 }
 
+fn parse_delimiter(delimiter: &str) -> MyResult<Delimiter> {
+    match delimiter.as_bytes() {
+        [] => Err(From::from("--delim \"\" must not be empty")),
+        [byte] => Ok(Delimiter::Byte(*byte)),
+        _ => Ok(Delimiter::Text(delimiter.to_string())),
+    }
+}
+
+fn parse_byte(flag: &str, value: &str) -> MyResult<u8> {
+    match value.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => Err(From::from(format!(
+            "{} \"{}\" must be a single byte",
+            flag, value
+        ))),
+    }
+}
+
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
@@ -110,31 +422,52 @@ fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
 }
 
 pub fn run(config: Config) -> MyResult<()> {
+    // A layout's header goes out once, however many files there are
+    let mut header = true;
     for filename in &config.files {
         match open(filename) {
             Err(err) => eprintln!("{}: {}", filename, err),
             Ok(file) => match &config.extract {
-                Fields(field_pos) => {
-                    let mut reader = ReaderBuilder::new()
-                        .delimiter(config.delimiter)
-                        .has_headers(false)
-                        .from_reader(file);
-                    let mut wtr = WriterBuilder::new()
-                        .delimiter(config.delimiter)
-                        .from_writer(io::stdout());
-                    for record in reader.records() {
-                        let record = record?;
-                        wtr.write_record(extract_fields(&record, field_pos))?;
+                Fields(_) | Names(_) => cut_fields(file, &config)?,
+                Bytes(byte_pos) => {
+                    let mut stdout = io::stdout();
+                    for line in file.split(b'\n') {
+                        let line = line?;
+                        let byte_pos = select_pos(byte_pos, line.len(), config.complement);
+                        let selected = if config.no_split {
+                            extract_whole_chars(&line, &byte_pos)
+                        } else {
+                            extract_bytes(&line, &byte_pos)
+                        };
+                        stdout.write_all(&selected)?;
+                        stdout.write_all(b"\n")?;
                     }
                 }
-                Bytes(byte_pos) => {
+                Chars(char_pos) if config.graphemes => {
                     for line in file.lines() {
-                        println!("{}", extract_bytes(&line?, &byte_pos));
+                        let line = line?;
+                        let num_graphemes = line.graphemes(true).count();
+                        let char_pos = select_pos(char_pos, num_graphemes, config.complement);
+                        println!("{}", extract_graphemes(&line, &char_pos));
                     }
                 }
                 Chars(char_pos) => {
                     for line in file.lines() {
-                        println!("{}", extract_chars(&line?, &char_pos));
+                        let line = line?;
+                        let num_chars = line.chars().count();
+                        let char_pos = select_pos(char_pos, num_chars, config.complement);
+                        println!("{}", extract_chars(&line, &char_pos));
+                    }
+                }
+                Layout(layout) => {
+                    cut_layout(file, layout, &config, header)?;
+                    header = false;
+                }
+                Columns(col_pos) => {
+                    for line in file.lines() {
+                        let line = line?;
+                        let col_pos = select_pos(col_pos, line.width(), config.complement);
+                        println!("{}", extract_columns(&line, &col_pos));
                     }
                 }
             },
@@ -143,85 +476,541 @@ pub fn run(config: Config) -> MyResult<()> {
     Ok(())
 }
 
+fn read_layout(filename: &str) -> MyResult<Vec<LayoutField>> {
+    let file = File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    let mut layout = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |pos| pos.line());
+        let layout_error = |msg: &str| format!("{}: line {}: {}", filename, line, msg);
+        if record.len() < 3 || record.len() > 4 {
+            return Err(From::from(layout_error("expected name,start,width[,type]")));
+        }
+        let start = parse_index(&record[1]).map_err(|e| layout_error(&e))?;
+        let width = record[2]
+            .parse::<NonZeroUsize>()
+            .map_err(|_| layout_error(&format!("illegal width: \"{}\"", &record[2])))?;
+        let kind = match record.get(3).unwrap_or("text") {
+            "" | "text" | "string" => FieldType::Text,
+            "int" | "integer" => FieldType::Integer,
+            "num" | "decimal" => FieldType::Decimal,
+            other => {
+                return Err(From::from(layout_error(&format!(
+                    "unknown type: \"{}\"",
+                    other
+                ))))
+            }
+        };
+        layout.push(LayoutField {
+            name: record[0].to_string(),
+            pos: start..start + usize::from(width),
+            kind,
+        });
+    }
+    if layout.is_empty() {
+        return Err(From::from(format!("{}: layout has no fields", filename)));
+    }
+    Ok(layout)
+}
+
+fn select_layout(layout: Vec<LayoutField>, names: &[String]) -> MyResult<Vec<LayoutField>> {
+    let headers: StringRecord = layout.iter().map(|field| &field.name).collect();
+    Ok(resolve_names(names, &headers)?
+        .into_iter()
+        .flatten()
+        .map(|i| layout[i].clone())
+        .collect())
+}
+
+fn parse_value(raw: &str, kind: FieldType) -> Result<Value, String> {
+    match kind {
+        _ if raw.is_empty() => Ok(Value::Null),
+        FieldType::Text => Ok(Value::from(raw)),
+        FieldType::Integer => raw
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| format!("\"{}\" is not an integer", raw)),
+        FieldType::Decimal => raw
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .map(Value::from)
+            .ok_or_else(|| format!("\"{}\" is not a decimal", raw)),
+    }
+}
+
+fn cut_layout(
+    file: impl BufRead,
+    layout: &[LayoutField],
+    config: &Config,
+    header: bool,
+) -> MyResult<()> {
+    let mut stdout = io::stdout();
+    let mut wtr = WriterBuilder::new()
+        .delimiter(if config.format == OutputFormat::Tsv {
+            b'\t'
+        } else {
+            b','
+        })
+        .from_writer(io::stdout());
+    if header && config.format != OutputFormat::Json {
+        wtr.write_record(layout.iter().map(|field| &field.name))?;
+    }
+
+    for (line_num, line) in file.split(b'\n').enumerate() {
+        let line = line?;
+        let mut values = vec![];
+        for field in layout {
+            let raw = if config.char_units {
+                extract_chars(
+                    &String::from_utf8_lossy(&line),
+                    std::slice::from_ref(&field.pos),
+                )
+            } else {
+                String::from_utf8_lossy(&extract_bytes(&line, std::slice::from_ref(&field.pos)))
+                    .into_owned()
+            };
+            let raw = raw.trim().to_string();
+            let value = parse_value(&raw, field.kind)
+                .map_err(|e| format!("line {}: column \"{}\": {}", line_num + 1, field.name, e))?;
+            values.push((raw, value));
+        }
+
+        if config.format == OutputFormat::Json {
+            let object: Map<String, Value> = layout
+                .iter()
+                .zip(values)
+                .map(|(field, (_, value))| (field.name.clone(), value))
+                .collect();
+            writeln!(stdout, "{}", Value::Object(object))?;
+        } else {
+            wtr.write_record(values.iter().map(|(raw, _)| raw))?;
+        }
+    }
+    Ok(())
+}
+
+fn cut_fields(file: impl BufRead, config: &Config) -> MyResult<()> {
+    match &config.delimiter {
+        Delimiter::Byte(delimiter) => {
+            let dialect = &config.dialect;
+            // Always read flexibly so lines without a delimiter can pass
+            // through; ragged rows are checked below instead
+            let reader = ReaderBuilder::new()
+                .delimiter(*delimiter)
+                .has_headers(false)
+                .flexible(true)
+                .quote(dialect.quote)
+                .escape(dialect.escape)
+                .double_quote(dialect.double_quote)
+                .comment(dialect.comment)
+                .from_reader(file);
+            let mut num_fields = None;
+            let records = reader.into_records().map(move |record| {
+                let record = record?;
+                if !dialect.flexible && record.len() > 1 {
+                    match num_fields {
+                        Some(num) if num != record.len() => {
+                            let line = record.position().map_or(0, |pos| pos.line());
+                            return Err(From::from(format!(
+                                "line {}: found record with {} fields, but the previous \
+record has {} fields (use --flexible to allow this)",
+                                line,
+                                record.len(),
+                                num
+                            )));
+                        }
+                        _ => num_fields = Some(record.len()),
+                    }
+                }
+                Ok(record)
+            });
+            let output_delimiter = match config.output_delimiter.as_bytes() {
+                [byte] => *byte,
+                // The CSV writer only takes one byte, so join anything
+                // longer by hand, without quoting, as GNU cut does
+                _ => {
+                    let mut stdout = io::stdout();
+                    return select_fields(records, config, |fields| {
+                        writeln!(stdout, "{}", fields.join(&config.output_delimiter))?;
+                        Ok(())
+                    });
+                }
+            };
+            let mut wtr = WriterBuilder::new()
+                .delimiter(output_delimiter)
+                .flexible(true)
+                .quote(dialect.quote)
+                .escape(dialect.escape.unwrap_or(b'\\'))
+                .double_quote(dialect.escape.is_none() && dialect.double_quote)
+                .quote_style(dialect.quote_style)
+                .from_writer(io::stdout());
+            select_fields(records, config, |fields| Ok(wtr.write_record(fields)?))
+        }
+        delimiter => {
+            let mut stdout = io::stdout();
+            let records = file.lines().map(|line| Ok(split_line(&line?, delimiter)));
+            select_fields(records, config, |fields| {
+                writeln!(stdout, "{}", fields.join(&config.output_delimiter))?;
+                Ok(())
+            })
+        }
+    }
+}
+
+fn split_line(line: &str, delimiter: &Delimiter) -> StringRecord {
+    match delimiter {
+        Delimiter::Byte(byte) => line.split(char::from(*byte)).collect(),
+        Delimiter::Text(text) => line.split(text.as_str()).collect(),
+        Delimiter::Pattern(re) => {
+            // Like awk, a delimiter at either end doesn't make an empty field
+            let start = match re.find(line) {
+                Some(m) if m.start() == 0 => m.end(),
+                _ => 0,
+            };
+            let end = match re.find_iter(&line[start..]).last() {
+                Some(m) if m.start() < m.end() && start + m.end() == line.len() => {
+                    start + m.start()
+                }
+                _ => line.len(),
+            };
+            re.split(&line[start..end]).collect()
+        }
+    }
+}
+
+fn select_fields(
+    mut records: impl Iterator<Item = MyResult<StringRecord>>,
+    config: &Config,
+    mut emit: impl FnMut(&[&str]) -> MyResult<()>,
+) -> MyResult<()> {
+    let field_pos = match &config.extract {
+        Names(names) => {
+            let headers = match records.next() {
+                Some(headers) => headers?,
+                None => return Ok(()),
+            };
+            // Names come out in the order given, since exports move columns around
+            let field_pos = resolve_names(names, &headers)?;
+            let header_pos = select_pos(&field_pos, headers.len(), config.complement);
+            emit(&extract_fields(&headers, &header_pos))?;
+            field_pos
+        }
+        Fields(field_pos) => field_pos.clone(),
+        _ => unreachable!(),
+    };
+
+    for record in records {
+        let record = record?;
+        if record.len() < 2 {
+            // Lines without a delimiter are passed through
+            if !config.only_delimited {
+                emit(&record.iter().collect::<Vec<_>>())?;
+            }
+            continue;
+        }
+        let field_pos = select_pos(&field_pos, record.len(), config.complement);
+        emit(&extract_fields(&record, &field_pos))?;
+    }
+    Ok(())
+}
+
+fn select_pos(pos: &[Range<usize>], len: usize, complement: bool) -> PositionList {
+    if !complement {
+        return pos.to_vec();
+    }
+    let mut selected = vec![false; len];
+    for range in pos {
+        let end = range.end.min(len);
+        if range.start < end {
+            selected[range.start..end].fill(true);
+        }
+    }
+    let mut ranges: PositionList = vec![];
+    for (i, _) in selected.iter().enumerate().filter(|(_, &sel)| !sel) {
+        match ranges.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
 fn extract_chars(line: &str, char_pos: &[Range<usize>]) -> String {
     let chars: Vec<_> = line.chars().collect();
     char_pos
         .iter()
         .cloned()
-        .flat_map(|range| range.filter_map(|i| chars.get(i)))
+        .flat_map(|range| range.map_while(|i| chars.get(i)))
         .collect()
 }
 
-fn extract_bytes(line: &str, byte_pos: &[Range<usize>]) -> String {
-    let bytes = line.as_bytes();
-    let selected: Vec<_> = byte_pos
+fn extract_graphemes(line: &str, char_pos: &[Range<usize>]) -> String {
+    let graphemes: Vec<_> = line.graphemes(true).collect();
+    char_pos
         .iter()
         .cloned()
-        .flat_map(|range| range.filter_map(|i| bytes.get(i)).copied())
+        .flat_map(|range| range.map_while(|i| graphemes.get(i).copied()))
+        .collect()
+}
+
+fn extract_columns(line: &str, col_pos: &[Range<usize>]) -> String {
+    // Pair each grapheme with the display columns it occupies
+    let mut col = 0;
+    let graphemes: Vec<_> = line
+        .graphemes(true)
+        .map(|grapheme| {
+            let start = col;
+            col += grapheme.width();
+            (start, col, grapheme)
+        })
         .collect();
-    String::from_utf8_lossy(&selected).into_owned()
+    // A wide character is kept only if every column it covers is selected;
+    // a zero-width one goes with the column it sits on
+    col_pos
+        .iter()
+        .flat_map(|range| {
+            graphemes
+                .iter()
+                .filter(move |(start, end, _)| {
+                    range.start <= *start && (*end).max(start + 1) <= range.end
+                })
+                .map(|(_, _, grapheme)| *grapheme)
+        })
+        .collect()
+}
+
+fn extract_bytes(line: &[u8], byte_pos: &[Range<usize>]) -> Vec<u8> {
+    byte_pos
+        .iter()
+        .cloned()
+        .flat_map(|range| range.map_while(|i| line.get(i)).copied())
+        .collect()
+}
+
+fn extract_whole_chars(line: &[u8], byte_pos: &[Range<usize>]) -> Vec<u8> {
+    // Find the byte span of each character; UTF-8 continuation
+    // bytes all look like 0b10xxxxxx
+    let mut spans: PositionList = vec![];
+    for (i, byte) in line.iter().enumerate() {
+        match spans.last_mut() {
+            Some(span) if byte & 0xC0 == 0x80 => span.end = i + 1,
+            _ => spans.push(i..i + 1),
+        }
+    }
+    // As with POSIX cut -n, a character is kept when its last byte is selected
+    byte_pos
+        .iter()
+        .flat_map(|range| {
+            spans
+                .iter()
+                .filter(move |span| range.contains(&(span.end - 1)))
+                .flat_map(|span| &line[span.clone()])
+        })
+        .copied()
+        .collect()
 }
 
 fn parse_index(input: &str) -> Result<usize, String> {
     let value_error = || format!("illegal list value: \"{}\"", input);
-    input
-        .starts_with('+')
-        .then(|| Err(value_error()))
-        .unwrap_or_else(|| {
-            input
-                .parse::<NonZeroUsize>()
-                .map(|n| usize::from(n) - 1)
-                .map_err(|_| value_error())
-        })
+    if input.starts_with('+') {
+        Err(value_error())
+    } else {
+        input
+            .parse::<NonZeroUsize>()
+            .map(|n| usize::from(n) - 1)
+            .map_err(|_| value_error())
+    }
 }
 
 fn parse_pos(range: &str) -> MyResult<PositionList> {
-    let range_re = Regex::new(r"^(\d+)-(\d+)$").unwrap();
+    let range_re = Regex::new(r"^(\d+)?-(\d+)?$").unwrap();
     range
         .split(',')
-        .into_iter()
         .map(|val| {
             parse_index(val).map(|n| n..n + 1).or_else(|e| {
-                range_re.captures(val).ok_or(e).and_then(|captures| {
-                    let n1 = parse_index(&captures[1])?;
-                    let n2 = parse_index(&captures[2])?;
-                    if n1 >= n2 {
-                        return Err(format!(
-                            "First number in range ({}) \
-must be lower than second number ({})",
-                            n1 + 1,
-                            n2 + 1
-                        ));
-                    }
-                    Ok(n1..n2 + 1)
-                })
+                range_re
+                    .captures(val)
+                    .ok_or(e.clone())
+                    .and_then(|captures| {
+                        let n1 = captures.get(1).map(|m| parse_index(m.as_str()));
+                        let n2 = captures.get(2).map(|m| parse_index(m.as_str()));
+                        match (n1.transpose()?, n2.transpose()?) {
+                            (None, None) => Err(e),
+                            (n1, n2) => {
+                                let n1 = n1.unwrap_or(0);
+                                let n2 = n2.unwrap_or(usize::MAX - 1);
+                                if n1 > n2 {
+                                    return Err(format!(
+                                        "First number in range ({}) \
+must not be greater than second number ({})",
+                                        n1 + 1,
+                                        n2 + 1
+                                    ));
+                                }
+                                Ok(n1..n2 + 1)
+                            }
+                        }
+                    })
             })
         })
         .collect::<Result<_, _>>()
         .map_err(From::from)
 }
 
+fn parse_names(names: &str) -> MyResult<Vec<String>> {
+    // Parse the list like a CSV row so quoted names may contain commas
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(names.as_bytes());
+    let record = reader.records().next().transpose()?.unwrap_or_default();
+    if record.is_empty() || record.iter().any(|name| name.is_empty()) {
+        return Err(From::from(format!("illegal list value: \"{}\"", names)));
+    }
+    Ok(record.iter().map(String::from).collect())
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let pattern: String = glob
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            _ => regex::escape(&c.to_string()),
+        })
+        .collect();
+    format!("^{}$", pattern)
+}
+
+fn resolve_names(names: &[String], headers: &StringRecord) -> MyResult<PositionList> {
+    let index_of = |name: &str| headers.iter().position(|header| header == name);
+    let mut field_pos = vec![];
+    for name in names {
+        let pattern = if name.len() > 1 && name.starts_with('/') && name.ends_with('/') {
+            Some(name[1..name.len() - 1].to_string())
+        } else if index_of(name).is_none() && name.contains(['*', '?']) {
+            Some(glob_to_regex(name))
+        } else {
+            None
+        };
+
+        if let Some(pattern) = pattern {
+            let name_re =
+                Regex::new(&pattern).map_err(|_| format!("Invalid pattern \"{}\"", name))?;
+            let matched: PositionList = headers
+                .iter()
+                .enumerate()
+                .filter(|(_, header)| name_re.is_match(header))
+                .map(|(i, _)| i..i + 1)
+                .collect();
+            if matched.is_empty() {
+                return Err(From::from(format!("no column matches \"{}\"", name)));
+            }
+            field_pos.extend(matched);
+        } else if let Some(i) = index_of(name) {
+            field_pos.push(i..i + 1);
+        } else {
+            // Try every hyphen, since names themselves may contain one
+            let range = name.match_indices('-').find_map(|(i, _)| {
+                let n1 = index_of(&name[..i])?;
+                let n2 = index_of(&name[i + 1..])?;
+                Some((n1, n2))
+            });
+            match range {
+                Some((n1, n2)) if n1 > n2 => {
+                    return Err(From::from(format!(
+                        "First column in range ({}) must not come after second column ({})",
+                        &headers[n1], &headers[n2]
+                    )))
+                }
+                Some((n1, n2)) => field_pos.push(n1..n2 + 1),
+                None => return Err(From::from(format!("unknown column \"{}\"", name))),
+            }
+        }
+    }
+    Ok(field_pos)
+}
+
+fn sort_pos(mut pos: PositionList) -> PositionList {
+    pos.sort_by_key(|range| range.start);
+    let mut merged: PositionList = vec![];
+    for range in pos {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
 fn extract_fields<'a>(record: &'a StringRecord, field_pos: &[Range<usize>]) -> Vec<&'a str> {
     field_pos
         .iter()
         .cloned()
-        .flat_map(|range| range.filter_map(|i| record.get(i)))
+        .flat_map(|range| range.map_while(|i| record.get(i)))
         .collect()
 }
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod unit_tests {
-    use super::{extract_bytes, extract_chars, extract_fields, parse_pos};
+    use super::{
+        extract_bytes, extract_chars, extract_columns, extract_fields, extract_graphemes,
+        extract_whole_chars, parse_names, parse_pos, parse_value, resolve_names, select_layout,
+        select_pos, sort_pos, split_line, Delimiter, PositionList,
+    };
     use csv::StringRecord;
+    use regex::Regex;
+    use std::ops::Range;
 
     #[test]
     fn test_extract_bytes() {
-        assert_eq!(extract_bytes("ábc", &[0..1]), "�".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..2]), "á".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..3]), "áb".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..4]), "ábc".to_string());
-        assert_eq!(extract_bytes("ábc", &[3..4, 2..3]), "cb".to_string());
-        assert_eq!(extract_bytes("ábc", &[0..2, 5..6]), "á".to_string());
+        assert_eq!(extract_bytes("ábc".as_bytes(), &[0..1]), b"\xC3");
+        assert_eq!(extract_bytes("ábc".as_bytes(), &[0..2]), "á".as_bytes());
+        assert_eq!(extract_bytes("ábc".as_bytes(), &[0..3]), "áb".as_bytes());
+        assert_eq!(extract_bytes("ábc".as_bytes(), &[0..4]), "ábc".as_bytes());
+        assert_eq!(extract_bytes("ábc".as_bytes(), &[3..4, 2..3]), b"cb");
+        assert_eq!(
+            extract_bytes("ábc".as_bytes(), &[0..2, 5..6]),
+            "á".as_bytes()
+        );
+        assert_eq!(extract_bytes(b"\xFFa", &[0..1]), b"\xFF");
+    }
+
+    #[test]
+    fn test_extract_whole_chars() {
+        assert_eq!(extract_whole_chars("ábc".as_bytes(), &[0..1]), b"");
+        assert_eq!(
+            extract_whole_chars("ábc".as_bytes(), &[1..2]),
+            "á".as_bytes()
+        );
+        assert_eq!(
+            extract_whole_chars("ábc".as_bytes(), &[0..2]),
+            "á".as_bytes()
+        );
+        assert_eq!(
+            extract_whole_chars("ábc".as_bytes(), &[1..3]),
+            "áb".as_bytes()
+        );
+        assert_eq!(
+            extract_whole_chars("ábc".as_bytes(), &[3..4, 0..2]),
+            "cá".as_bytes()
+        );
+        assert_eq!(
+            extract_whole_chars("日本".as_bytes(), &[0..4]),
+            "日".as_bytes()
+        );
+        assert_eq!(
+            extract_whole_chars("日本".as_bytes(), &[0..usize::MAX]),
+            "日本".as_bytes()
+        );
+        assert_eq!(extract_whole_chars(b"", &[0..1]), b"");
     }
 
     #[test]
@@ -275,25 +1064,20 @@ mod unit_tests {
         assert!(res.is_err());
         let res = parse_pos("1,");
         assert!(res.is_err());
-        let res = parse_pos("1-");
-        assert!(res.is_err());
         let res = parse_pos("1-1-1");
         assert!(res.is_err());
         let res = parse_pos("1-1-a");
         assert!(res.is_err());
-        // First number must be less than second
-
-        let res = parse_pos("1-1");
+        let res = parse_pos("1--");
         assert!(res.is_err());
-        assert_eq!(
-            res.unwrap_err().to_string(),
-            "First number in range (1) must be lower than second number (1)"
-        );
+        let res = parse_pos("--1");
+        assert!(res.is_err());
+        // First number must not be greater than second
         let res = parse_pos("2-1");
         assert!(res.is_err());
         assert_eq!(
             res.unwrap_err().to_string(),
-            "First number in range (2) must be lower than second number (1)"
+            "First number in range (2) must not be greater than second number (1)"
         );
         // All the following are acceptable
         let res = parse_pos("1");
@@ -320,6 +1104,19 @@ mod unit_tests {
         let res = parse_pos("15,19-20");
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), vec![14..15, 18..20]);
+        // Single-element and open-ended ranges
+        let res = parse_pos("1-1");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![0..1]);
+        let res = parse_pos("3-");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![2..usize::MAX]);
+        let res = parse_pos("-5");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![0..5]);
+        let res = parse_pos("-2,4-");
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), vec![0..2, 3..usize::MAX]);
     }
 
     #[test]
@@ -331,4 +1128,150 @@ mod unit_tests {
         assert_eq!(extract_fields(&rec, &[0..1, 3..4]), &["Captain"]);
         assert_eq!(extract_fields(&rec, &[1..2, 0..1]), &["Sham", "Captain"]);
     }
+
+    #[test]
+    fn test_select_pos() {
+        assert_eq!(select_pos(&[1..2, 0..1], 3, false), vec![1..2, 0..1]);
+        assert_eq!(select_pos(&[1..2], 3, true), vec![0..1, 2..3]);
+        assert_eq!(select_pos(&[0..2], 3, true), vec![2..3]);
+        assert_eq!(select_pos(&[2..3, 0..1], 5, true), vec![1..2, 3..5]);
+        assert_eq!(select_pos(&[0..10], 3, true), PositionList::new());
+        assert_eq!(select_pos(&[4..5], 3, true), vec![0..3]);
+    }
+
+    #[test]
+    fn test_sort_pos() {
+        assert_eq!(sort_pos(PositionList::new()), PositionList::new());
+        assert_eq!(sort_pos(vec![2..3, 0..1]), vec![0..1, 2..3]);
+        assert_eq!(sort_pos(vec![1..2, 0..1]), vec![0..2]);
+        assert_eq!(sort_pos(vec![0..3, 1..2, 1..2]), vec![0..3]);
+        assert_eq!(sort_pos(vec![4..usize::MAX, 0..5]), vec![0..usize::MAX]);
+    }
+
+    #[test]
+    fn test_parse_names() {
+        assert!(parse_names("").is_err());
+        assert!(parse_names("a,").is_err());
+        assert_eq!(parse_names("a").unwrap(), &["a"]);
+        assert_eq!(
+            parse_names("name,\"last, first\",created at").unwrap(),
+            &["name", "last, first", "created at"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_names() {
+        let headers = StringRecord::from(vec!["id", "name", "e-mail", "addr1", "addr2"]);
+        let resolve = |names: &[&str]| {
+            let names: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+            resolve_names(&names, &headers)
+        };
+        assert_eq!(resolve(&["name"]).unwrap(), vec![1..2]);
+        assert_eq!(resolve(&["e-mail", "id"]).unwrap(), vec![2..3, 0..1]);
+        assert_eq!(resolve(&["id-e-mail"]).unwrap(), vec![0..3]);
+        assert_eq!(resolve(&["name-name"]).unwrap(), vec![1..2]);
+        assert_eq!(resolve(&["addr*"]).unwrap(), vec![3..4, 4..5]);
+        assert_eq!(resolve(&["/^[a-z]+$/"]).unwrap(), vec![0..1, 1..2]);
+
+        let res = resolve(&["nope"]);
+        assert_eq!(res.unwrap_err().to_string(), "unknown column \"nope\"");
+        let res = resolve(&["zip*"]);
+        assert_eq!(res.unwrap_err().to_string(), "no column matches \"zip*\"");
+        let res = resolve(&["e-mail-id"]);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "First column in range (e-mail) must not come after second column (id)"
+        );
+    }
+
+    #[test]
+    fn test_split_line() {
+        let text = Delimiter::Text("::".to_string());
+        assert_eq!(split_line("a::b::c", &text), vec!["a", "b", "c"]);
+        assert_eq!(split_line("a:b", &text), vec!["a:b"]);
+        let text = Delimiter::Text("│".to_string());
+        assert_eq!(split_line("á│b│", &text), vec!["á", "b", ""]);
+        let pattern = Delimiter::Pattern(Regex::new(r"\s+").unwrap());
+        assert_eq!(
+            split_line("PID  TTY\tCMD", &pattern),
+            vec!["PID", "TTY", "CMD"]
+        );
+        assert_eq!(split_line("", &pattern), vec![""]);
+        assert_eq!(
+            split_line("    1 ?   init  ", &pattern),
+            vec!["1", "?", "init"]
+        );
+        assert_eq!(split_line("   ", &pattern), vec![""]);
+    }
+
+    #[test]
+    fn test_extract_graphemes() {
+        // "e" followed by a combining acute accent
+        assert_eq!(extract_graphemes("e\u{301}bc", &[0..1]), "e\u{301}");
+        assert_eq!(extract_chars("e\u{301}bc", &[0..1]), "e");
+        // A family emoji joined with ZWJs is one cluster
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let line = format!("{}ab", family);
+        assert_eq!(extract_graphemes(&line, &[0..1]), family);
+        assert_eq!(extract_graphemes(&line, &[1..3]), "ab");
+        assert_eq!(
+            extract_graphemes(&line, &[2..usize::MAX, 0..1]),
+            format!("b{}", family)
+        );
+        assert_eq!(extract_graphemes("", &[0..1]), "");
+    }
+
+    #[test]
+    fn test_extract_columns() {
+        assert_eq!(extract_columns("abc", &[1..3]), "bc");
+        // Each CJK character is two columns wide
+        assert_eq!(extract_columns("日本語", &[0..2]), "日");
+        assert_eq!(extract_columns("日本語", &[0..4]), "日本");
+        assert_eq!(extract_columns("日本語", &[0..3]), "日");
+        assert_eq!(extract_columns("日本語", &[1..4]), "本");
+        assert_eq!(extract_columns("a日b", &[3..usize::MAX]), "b");
+        assert_eq!(extract_columns("e\u{301}x", &[0..1]), "e\u{301}");
+    }
+
+    #[test]
+    fn test_parse_value() {
+        use super::FieldType::*;
+        use serde_json::{json, Value};
+
+        assert_eq!(parse_value("", Integer), Ok(Value::Null));
+        assert_eq!(parse_value("abc", Text), Ok(json!("abc")));
+        assert_eq!(parse_value("0042", Integer), Ok(json!(42)));
+        assert_eq!(parse_value("-7", Integer), Ok(json!(-7)));
+        assert_eq!(parse_value("12.50", Decimal), Ok(json!(12.5)));
+        assert_eq!(
+            parse_value("4x", Integer),
+            Err("\"4x\" is not an integer".to_string())
+        );
+        assert_eq!(
+            parse_value("NaN", Decimal),
+            Err("\"NaN\" is not a decimal".to_string())
+        );
+    }
+
+    #[test]
+    fn test_select_layout() {
+        use super::{FieldType, LayoutField};
+
+        let field = |name: &str, pos: Range<usize>| LayoutField {
+            name: name.to_string(),
+            pos,
+            kind: FieldType::Text,
+        };
+        let layout = vec![
+            field("id", 0..4),
+            field("name", 4..14),
+            field("city", 14..24),
+        ];
+        let names = vec!["city".to_string(), "id".to_string()];
+        assert_eq!(
+            select_layout(layout.clone(), &names).unwrap(),
+            vec![field("city", 14..24), field("id", 0..4)]
+        );
+        assert!(select_layout(layout, &["zip".to_string()]).is_err());
+    }
 }
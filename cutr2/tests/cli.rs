@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
@@ -9,6 +11,14 @@ const PRG: &str = "cutr";
 const CSV: &str = "tests/inputs/movies1.csv";
 const TSV: &str = "tests/inputs/movies1.tsv";
 const BOOKS: &str = "tests/inputs/books.csv";
+const BOOKS_TSV: &str = "tests/inputs/books.tsv";
+const NODELIM: &str = "tests/inputs/nodelim.tsv";
+const COLONS: &str = "tests/inputs/movies1.colons";
+const BOXED: &str = "tests/inputs/movies1.boxed";
+const PS: &str = "tests/inputs/ps.txt";
+const PS_ALIGNED: &str = "tests/inputs/ps_aligned.txt";
+const ACCOUNTS: &str = "tests/inputs/accounts.txt";
+const LAYOUT: &str = "tests/inputs/accounts.layout";
 
 // --------------------------------------------------
 fn random_string() -> String {
@@ -55,7 +65,10 @@ fn dies(args: &[&str], expected: &str) -> TestResult {
 // --------------------------------------------------
 #[test]
 fn dies_not_enough_args() -> TestResult {
-    dies(&[CSV], "Must have --fields, --bytes, or --chars")
+    dies(
+        &[CSV],
+        "Must have --fields, --names, --bytes, --chars, --columns, or --layout",
+    )
 }
 
 // --------------------------------------------------
@@ -93,16 +106,25 @@ fn dies_bad_digit_chars() -> TestResult {
 fn dies_empty_delimiter() -> TestResult {
     dies(
         &[CSV, "-f", "1", "-d", ""],
-        "--delim \"\" must be a single byte",
+        "--delim \"\" must not be empty",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_regex_delimiter() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--regex-delimiter", "("],
+        "Invalid --regex-delimiter \"(\"",
     )
 }
 
 // --------------------------------------------------
 #[test]
-fn dies_bad_delimiter() -> TestResult {
+fn dies_delimiter_and_regex_delimiter() -> TestResult {
     dies(
-        &[CSV, "-f", "1", "-d", ",,"],
-        "--delim \",,\" must be a single byte",
+        &[CSV, "-f", "1", "-d", ",", "--regex-delimiter", ","],
+        "--delim cannot be used with --regex-delimiter",
     )
 }
 
@@ -158,14 +180,13 @@ fn run(args: &[&str], expected_file: &str) -> TestResult {
 }
 
 // --------------------------------------------------
-fn run_lossy(args: &[&str], expected_file: &str) -> TestResult {
-    let contents = fs::read(expected_file)?;
-    let expected = String::from_utf8_lossy(&contents);
+fn run_bytes(args: &[&str], expected_file: &str) -> TestResult {
+    let expected = fs::read(expected_file)?;
     Command::cargo_bin(PRG)?
         .args(args)
         .assert()
         .success()
-        .stdout(predicate::str::contains(expected));
+        .stdout(expected);
     Ok(())
 }
 
@@ -274,7 +295,7 @@ fn tsv_b2() -> TestResult {
 // --------------------------------------------------
 #[test]
 fn tsv_b8() -> TestResult {
-    run_lossy(&[TSV, "-b", "8"], "tests/expected/movies1.tsv.b8.out")
+    run_bytes(&[TSV, "-b", "8"], "tests/expected/movies1.tsv.b8.out")
 }
 
 // --------------------------------------------------
@@ -292,7 +313,7 @@ fn tsv_b2_3() -> TestResult {
 // --------------------------------------------------
 #[test]
 fn tsv_b1_8() -> TestResult {
-    run_lossy(&[TSV, "-b", "1-8"], "tests/expected/movies1.tsv.b1-8.out")
+    run_bytes(&[TSV, "-b", "1-8"], "tests/expected/movies1.tsv.b1-8.out")
 }
 
 // --------------------------------------------------
@@ -339,3 +360,536 @@ fn books() -> TestResult {
         "tests/inputs/books.f1-3.out",
     )
 }
+
+// --------------------------------------------------
+#[test]
+fn csv_output_delimiter_string() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "-f", "1,3", "--output-delimiter", "::"],
+        "tests/expected/movies1.csv.f1,3.ocolons.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_only_delimited_chars() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([TSV, "-c", "1", "-s"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_f2_complement() -> TestResult {
+    run(
+        &[TSV, "-f", "2", "--complement"],
+        "tests/expected/movies1.tsv.f2.complement.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_c1_3_complement() -> TestResult {
+    run(
+        &[TSV, "-c", "1-3", "--complement"],
+        "tests/expected/movies1.tsv.c1-3.complement.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn books_output_delimiter() -> TestResult {
+    run(
+        &[BOOKS_TSV, "-f", "1-3", "--output-delimiter", ","],
+        "tests/expected/books.tsv.f1-3.ocomma.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn nodelim_f2() -> TestResult {
+    run(&[NODELIM, "-f", "2"], "tests/expected/nodelim.tsv.f2.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn nodelim_f2_only_delimited() -> TestResult {
+    run(
+        &[NODELIM, "-f", "2", "--only-delimited"],
+        "tests/expected/nodelim.tsv.f2.s.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_decreasing_range() -> TestResult {
+    dies(
+        &[TSV, "-f", "3-2"],
+        "First number in range (3) must not be greater than second number (2)",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_f3_1() -> TestResult {
+    run(&[TSV, "-f", "3,1"], "tests/expected/movies1.tsv.f3,1.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_f3_1_keep_order() -> TestResult {
+    run(
+        &[TSV, "-f", "3,1", "--keep-order"],
+        "tests/expected/movies1.tsv.f3,1.keep.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_f2_open() -> TestResult {
+    run(&[TSV, "-f", "2-"], "tests/expected/movies1.tsv.f2-.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_c_open_3() -> TestResult {
+    run(&[TSV, "-c", "-3"], "tests/expected/movies1.tsv.c-3.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_b2_2_1_3() -> TestResult {
+    run(
+        &[TSV, "-b", "2-2,1-3"],
+        "tests/expected/movies1.tsv.b2-2,1-3.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_unknown_name() -> TestResult {
+    dies(&[CSV, "-d", ",", "-F", "nope"], "unknown column \"nope\"")
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_names_fields() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([CSV, "-F", "title", "-f", "1"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_names() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "-F", "director,title"],
+        "tests/expected/movies1.csv.Fdirector,title.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_names_keep_order() -> TestResult {
+    // Names are always in the order given
+    run(
+        &[CSV, "-d", ",", "-F", "director,title", "--keep-order"],
+        "tests/expected/movies1.csv.Fdirector,title.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_names_range() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "--names", "title-year"],
+        "tests/expected/movies1.csv.Ftitle-year.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_names_patterns() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "-F", "/^d/,y*"],
+        "tests/expected/movies1.csv.Fpatterns.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_names_complement() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "-F", "year", "--complement"],
+        "tests/expected/movies1.csv.Fyear.complement.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn colons_f1_3() -> TestResult {
+    run(
+        &[COLONS, "-d", "::", "-f", "1,3"],
+        "tests/expected/movies1.colons.f1,3.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn colons_f1_3_output_delimiter() -> TestResult {
+    run(
+        &[COLONS, "-d", "::", "-f", "1,3", "--output-delimiter", " | "],
+        "tests/expected/movies1.colons.f1,3.opipe.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn boxed_names() -> TestResult {
+    run(
+        &[BOXED, "-d", "│", "-F", "director,year"],
+        "tests/expected/movies1.boxed.Fdirector,year.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn ps_regex_delimiter() -> TestResult {
+    run(
+        &[PS, "--regex-delimiter", r"\s+", "-f", "2,4-"],
+        "tests/expected/ps.txt.f2,4-.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn ps_aligned_regex_delimiter() -> TestResult {
+    run(
+        &[PS_ALIGNED, "--regex-delimiter", r"\s+", "-f", "1,4"],
+        "tests/expected/ps_aligned.txt.f1,4.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_ragged() -> TestResult {
+    dies(
+        &["tests/inputs/movies2.tsv", "-f", "1"],
+        "line 2: found record with 3 fields, but the previous record has 2 fields",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_quote() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--quote", "''"],
+        "--quote \"''\" must be a single byte",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn ragged_flexible() -> TestResult {
+    run(
+        &["tests/inputs/movies2.tsv", "-f", "1", "--flexible"],
+        "tests/expected/movies2.tsv.f1.flexible.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quote_style_always() -> TestResult {
+    run(
+        &[CSV, "-d", ",", "-f", "1-2", "--quote-style", "always"],
+        "tests/expected/movies1.csv.f1-2.always.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quoted_field() -> TestResult {
+    run(
+        &["tests/inputs/movies2.csv", "-d", ",", "-f", "1"],
+        "tests/expected/movies2.csv.f1.dcomma.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_quote_style_never() -> TestResult {
+    run(
+        &[
+            "tests/inputs/movies2.csv",
+            "-d",
+            ",",
+            "-f",
+            "1",
+            "--quote-style",
+            "never",
+        ],
+        "tests/expected/movies2.csv.f1.never.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_single_quotes_and_comments() -> TestResult {
+    run(
+        &[
+            "tests/inputs/movies3.csv",
+            "-d",
+            ",",
+            "-f",
+            "1,3",
+            "--quote",
+            "'",
+            "--comment",
+            "#",
+        ],
+        "tests/expected/movies3.csv.f1,3.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn csv_escaped_quotes() -> TestResult {
+    run(
+        &[
+            "tests/inputs/movies4.csv",
+            "-d",
+            ",",
+            "-f",
+            "1",
+            "--escape",
+            "\\",
+            "--no-double-quote",
+        ],
+        "tests/expected/movies4.csv.f1.escape.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_csv_options_outside_csv() -> TestResult {
+    dies(
+        &[COLONS, "-d", "::", "-f", "1", "--quote-style", "always"],
+        "--quote-style requires --fields or --names with a one-byte --delim",
+    )?;
+    dies(
+        &[CSV, "--regex-delimiter", ",+", "-f", "1", "--flexible"],
+        "--flexible requires --fields or --names with a one-byte --delim",
+    )?;
+    dies(
+        &[CSV, "-c", "1", "--no-double-quote"],
+        "--no-double-quote requires --fields or --names with a one-byte --delim",
+    )?;
+    dies(
+        &[
+            CSV,
+            "-d",
+            ",",
+            "-f",
+            "1",
+            "--output-delimiter",
+            "::",
+            "--quote-style",
+            "never",
+        ],
+        "--quote-style requires a one-byte --output-delimiter",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_graphemes_without_chars() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([TSV, "-b", "1", "--graphemes"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn graphemes_c1_2() -> TestResult {
+    run(
+        &["tests/inputs/graphemes.txt", "-c", "1-2", "--graphemes"],
+        "tests/expected/graphemes.txt.c1-2.g.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn report_columns() -> TestResult {
+    run(
+        &["tests/inputs/report.txt", "--columns", "5-14"],
+        "tests/expected/report.txt.columns5-14.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn report_columns_complement() -> TestResult {
+    run(
+        &[
+            "tests/inputs/report.txt",
+            "--columns",
+            "1-4",
+            "--complement",
+        ],
+        "tests/expected/report.txt.columns1-4.complement.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_no_split_without_bytes() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([TSV, "-c", "1", "-n"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_b8_no_split() -> TestResult {
+    run(
+        &[TSV, "-b", "8", "-n"],
+        "tests/expected/movies1.tsv.b8.n.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn tsv_b1_8_no_split() -> TestResult {
+    run(
+        &[TSV, "-b", "1-8", "-n"],
+        "tests/expected/movies1.tsv.b1-8.n.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn bytes_invalid_utf8() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-b", "2-3"])
+        .write_stdin(&b"a\xFF\xFEb\n"[..])
+        .assert()
+        .success()
+        .stdout(&b"\xFF\xFE\n"[..]);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_bytes() -> TestResult {
+    run(
+        &["--layout", LAYOUT, ACCOUNTS],
+        "tests/expected/accounts.layout.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_two_files() -> TestResult {
+    run(
+        &["--layout", LAYOUT, ACCOUNTS, ACCOUNTS],
+        "tests/expected/accounts.layout.twice.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_chars() -> TestResult {
+    run(
+        &["--layout", LAYOUT, "--units", "chars", ACCOUNTS],
+        "tests/expected/accounts.layout.chars.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_json() -> TestResult {
+    run(
+        &[
+            "--layout", LAYOUT, "--units", "chars", "--format", "json", ACCOUNTS,
+        ],
+        "tests/expected/accounts.layout.json.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn layout_names_tsv() -> TestResult {
+    run(
+        &[
+            "--layout",
+            LAYOUT,
+            "--units",
+            "chars",
+            "--format",
+            "tsv",
+            "-F",
+            "balance,id",
+            ACCOUNTS,
+        ],
+        "tests/expected/accounts.layout.F.tsv.out",
+    )?;
+    run(
+        &[
+            "--layout",
+            LAYOUT,
+            "--units",
+            "chars",
+            "--format",
+            "tsv",
+            "-F",
+            "balance,id",
+            "--keep-order",
+            ACCOUNTS,
+        ],
+        "tests/expected/accounts.layout.F.tsv.out",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_layout_bad_value() -> TestResult {
+    dies(
+        &["--layout", LAYOUT, "tests/inputs/accounts_bad.txt"],
+        r#"line 1: column "balance": "12x" is not a decimal"#,
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_layout_with_fields() -> TestResult {
+    dies(
+        &["--layout", LAYOUT, "-f", "1", ACCOUNTS],
+        "cannot be used with",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_layout_complement() -> TestResult {
+    dies(
+        &["--layout", LAYOUT, "--complement", ACCOUNTS],
+        "--complement cannot be used with --layout",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_format_without_layout() -> TestResult {
+    dies(
+        &[CSV, "-f", "1", "--format", "json"],
+        "--format requires --layout",
+    )
+}
@@ -1,32 +1,241 @@
-use clap::{App, Arg};
-use regex::{Regex, RegexBuilder};
+use aho_corasick::{AhoCorasick, MatchKind};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{App, AppSettings, Arg};
+use flate2::read::MultiGzDecoder;
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    WalkBuilder,
+};
+use regex::bytes::{Regex, RegexBuilder};
+use serde_json::{json, Value};
 use std::{
+    collections::VecDeque,
+    env,
     error::Error,
+    ffi::OsString,
     fs::{self, File},
-    io::{self, BufRead, BufReader},
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write},
+    iter,
+    num::NonZeroUsize,
+    ops::Range,
+    path::Path,
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TryRecvError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
-use walkdir::WalkDir;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 #[derive(Debug)]
 pub struct Config {
-    pattern: Regex,
+    matcher: Matcher,
     files: Vec<String>,
     recursive: bool,
+    hidden: bool,
+    no_ignore: bool,
+    globs: Override,
+    max_depth: Option<usize>,
+    threads: usize,
+    search_zip: bool,
+    search_archives: bool,
     count: bool,
     invert_match: bool,
+    line_number: bool,
+    byte_offset: bool,
+    with_filename: Option<bool>,
+    label: String,
+    only_matching: bool,
+    replace: Option<String>,
+    write: bool,
+    dry_run: bool,
+    colors: Colors,
+    max_count: Option<usize>,
+    files_with_matches: bool,
+    files_without_match: bool,
+    quiet: bool,
+    json: bool,
+    binary: BinaryFiles,
+    before_context: usize,
+    after_context: usize,
+}
+
+/// Finds the patterns in a line
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Regex(Regex),
+    /// Plain strings, which can be searched for all at once
+    Literal(AhoCorasick),
+    #[cfg(feature = "pcre")]
+    Fancy(fancy_regex::Regex),
+    /// Patterns searched one by one, each match coming from the first of
+    /// them to match earliest in the line
+    Any(Vec<Matcher>),
+}
+
+/// How the patterns are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Regex,
+    Fixed,
+    /// Backtracking regexes, with a limit on the steps each search may take
+    Perl(usize),
+}
+
+/// What to do with files that look binary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryFiles {
+    Binary,
+    Text,
+    WithoutMatch,
+}
+
+/// Compressed inputs that -z can read, known by their extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// SGR sequences for each part of the output, empty for no color
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Colors {
+    selected_match: String,
+    context_match: String,
+    selected_line: String,
+    context_line: String,
+    file_name: String,
+    line_number: String,
+    byte_offset: String,
+    separator: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Line {
+    num: usize,
+    offset: usize,
+    text: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Match(Line),
+    Context(Line),
+    Break,
+    /// A binary file had its first match, with the offset of its first NUL
+    Binary(usize),
+}
+
+/// Receives search results as they are found
+trait Sink {
+    /// Handle one event, returning false to stop the search early
+    fn event(&mut self, event: Event) -> MyResult<bool>;
+}
+
+impl<F: FnMut(Event) -> MyResult<bool>> Sink for F {
+    fn event(&mut self, event: Event) -> MyResult<bool> {
+        self(event)
+    }
+}
+
+struct Printer<W: Write> {
+    out: W,
+    matcher: Matcher,
+    invert_match: bool,
+    only_matching: bool,
+    replace: Option<String>,
+    colors: Colors,
+    with_filename: bool,
+    line_number: bool,
+    byte_offset: bool,
+}
+
+/// Writes ripgrep-style JSON Lines messages for one file
+struct JsonPrinter<'a, W: Write> {
+    out: &'a mut W,
+    matcher: &'a Matcher,
+    path: &'a str,
+    begun: bool,
+    binary_offset: Option<usize>,
+    stats: Stats,
+}
+
+/// Totals reported by --json, for each file and for the whole run
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Stats {
+    elapsed: Duration,
+    searches: usize,
+    searches_with_match: usize,
+    bytes_searched: usize,
+    bytes_printed: usize,
+    matched_lines: usize,
+    matches: usize,
+}
+
+/// A selected line and what --replace turns it into
+struct Change {
+    line: Line,
+    new: Vec<u8>,
+}
+
+/// Counts the bytes a search actually reads
+struct Counted<R> {
+    inner: R,
+    bytes: usize,
+}
+
+/// The outcome of one finished file
+struct Searched {
+    count: usize,
+    stats: Stats,
+}
+
+/// What a worker sends back about one file, in the order it happens
+enum Piece {
+    Output(Vec<u8>),
+    Done(Result<Searched, String>),
+}
+
+/// Passes a file's output to the printing thread a chunk at a time, so
+/// nothing piles up in memory and slow inputs show up as they arrive
+struct Output {
+    tx: SyncSender<Piece>,
+    buf: Vec<u8>,
+    /// Send every finished line at once rather than waiting for a full chunk
+    eager: bool,
+    /// Written ahead of the next output, for separators between archive members
+    separator: Option<Vec<u8>>,
+    /// Whether anything has been written, whether or not it was sent yet
+    written: bool,
 }
 
 pub fn get_args() -> MyResult<Config> {
+    // Defaults go before the command line so that anything given there wins
+    let mut args: Vec<OsString> = env::args_os().collect();
+    if !args.iter().any(|arg| arg == "--no-config") {
+        if let Some(path) = env::var_os("GREPR_CONFIG_PATH").filter(|path| !path.is_empty()) {
+            let defaults = read_defaults(Path::new(&path))?;
+            args.splice(1..1, defaults.into_iter().map(OsString::from));
+        }
+    }
+
     let matches = App::new("grepr")
         .version("0.1.0")
         .author("Ken Youens-Clark <kyclark@gmail.com>")
         .about("Rust grep")
+        .after_help(
+            "Default options can be kept in a file named by GREPR_CONFIG_PATH, \
+             one per line, with # for comments.",
+        )
+        .setting(AppSettings::AllArgsOverrideSelf)
         .arg(
             Arg::with_name("pattern")
                 .value_name("PATTERN")
                 .help("Search pattern")
-                .required(true),
+                .required_unless_one(&["regexp", "pattern_file"]),
         )
         .arg(
             Arg::with_name("files")
@@ -36,12 +245,96 @@ pub fn get_args() -> MyResult<Config> {
                 .default_value("-")
                 .min_values(1),
         )
+        .arg(
+            Arg::with_name("regexp")
+                .value_name("PATTERN")
+                .help("Search pattern, may be repeated")
+                .short("e")
+                .long("regexp")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("pattern_file")
+                .value_name("FILE")
+                .help("Read patterns from FILE, one per line")
+                .short("f")
+                .long("file")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("fixed_strings")
+                .help("Patterns are plain strings")
+                .short("F")
+                .long("fixed-strings")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("perl_regexp")
+                .help("Patterns are Perl-style regexes with lookaround and backreferences")
+                .short("P")
+                .long("perl-regexp")
+                .takes_value(false)
+                .conflicts_with("fixed_strings"),
+        )
+        .arg(
+            Arg::with_name("backtrack_limit")
+                .value_name("NUM")
+                .help("Maximum backtracking steps per line with -P")
+                .long("backtrack-limit")
+                .default_value("1000000"),
+        )
+        .arg(
+            Arg::with_name("word_regexp")
+                .help("Match only whole words")
+                .short("w")
+                .long("word-regexp")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("line_regexp")
+                .help("Match only whole lines")
+                .short("x")
+                .long("line-regexp")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("insensitive")
                 .value_name("INSENSITIVE")
-                .help("Case-insensitive")
+                .help(
+                    "Case-insensitive, with Unicode case folding; -F needles fold \
+                     fully, so straße matches STRASSE, while regexes fold one \
+                     character to one",
+                )
                 .short("i")
                 .long("insensitive")
+                .overrides_with("smart_case")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("smart_case")
+                .help(
+                    "Case-insensitive unless the pattern has an uppercase letter, \
+                     folding case as -i does",
+                )
+                .short("S")
+                .long("smart-case")
+                .overrides_with("insensitive")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no_unicode")
+                .help("Match only ASCII with \\w, \\b, . and case folding")
+                .long("no-unicode")
+                .conflicts_with("perl_regexp")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no_config")
+                .help("Ignore the GREPR_CONFIG_PATH defaults file")
+                .long("no-config")
                 .takes_value(false),
         )
         .arg(
@@ -52,6 +345,60 @@ pub fn get_args() -> MyResult<Config> {
                 .long("recursive")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("hidden")
+                .help("Search hidden files and directories")
+                .long("hidden")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no_ignore")
+                .help("Don't respect .gitignore and .ignore files")
+                .long("no-ignore")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("include")
+                .value_name("GLOB")
+                .help("Search only files matching GLOB")
+                .long("include")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .value_name("GLOB")
+                .help("Skip files and directories matching GLOB")
+                .long("exclude")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("max_depth")
+                .value_name("NUM")
+                .help("Descend at most NUM directories")
+                .long("max-depth"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .value_name("NUM")
+                .help("Number of files to search in parallel")
+                .short("j")
+                .long("threads"),
+        )
+        .arg(
+            Arg::with_name("search_zip")
+                .help("Search in compressed files (.gz, .zst)")
+                .short("z")
+                .long("search-zip")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("search_archives")
+                .help("Search each file inside tar archives (.tar, .tar.gz, .tgz, .tar.zst)")
+                .long("search-archives")
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("count")
                 .value_name("COUNT")
@@ -68,181 +415,2367 @@ pub fn get_args() -> MyResult<Config> {
                 .long("invert-match")
                 .takes_value(false),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("line_number")
+                .help("Prefix each line with its line number")
+                .short("n")
+                .long("line-number")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("byte_offset")
+                .help("Prefix each line with its byte offset")
+                .short("b")
+                .long("byte-offset")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("with_filename")
+                .help("Print the file name for each match")
+                .short("H")
+                .long("with-filename")
+                .takes_value(false)
+                .overrides_with("no_filename"),
+        )
+        .arg(
+            Arg::with_name("no_filename")
+                .help("Suppress the file name prefix")
+                .short("h")
+                .long("no-filename")
+                .takes_value(false)
+                .overrides_with("with_filename"),
+        )
+        .arg(
+            Arg::with_name("label")
+                .value_name("LABEL")
+                .help("Display name for standard input")
+                .long("label")
+                .default_value("(standard input)"),
+        )
+        .arg(
+            Arg::with_name("only_matching")
+                .help("Print only the matched parts of a line")
+                .short("o")
+                .long("only-matching")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("replace")
+                .value_name("TEXT")
+                .help("Show each match replaced by TEXT, which may use $1 or ${name}")
+                .long("replace")
+                .allow_hyphen_values(true)
+                .conflicts_with_all(&["invert", "json"]),
+        )
+        .arg(
+            Arg::with_name("write")
+                .help("Save the replacements to the files")
+                .long("write")
+                .requires("replace")
+                .conflicts_with_all(&["quiet", "search_zip", "search_archives"])
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .help("Print the replacements as a unified diff instead")
+                .long("dry-run")
+                .requires("replace")
+                .conflicts_with_all(&["write", "quiet", "search_zip", "search_archives"])
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("color")
+                .value_name("WHEN")
+                .help("Highlight matches")
+                .long("color")
+                .alias("colour")
+                .possible_values(&["auto", "always", "never"])
+                .min_values(0)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("max_count")
+                .value_name("NUM")
+                .help("Stop after NUM selected lines")
+                .short("m")
+                .long("max-count"),
+        )
+        .arg(
+            Arg::with_name("files_with_matches")
+                .help("Print only the names of files with selected lines")
+                .short("l")
+                .long("files-with-matches")
+                .takes_value(false)
+                .overrides_with("files_without_match"),
+        )
+        .arg(
+            Arg::with_name("files_without_match")
+                .help("Print only the names of files without selected lines")
+                .short("L")
+                .long("files-without-match")
+                .takes_value(false)
+                .overrides_with("files_with_matches"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Print nothing, stop at the first selected line")
+                .short("q")
+                .long("quiet")
+                .alias("silent")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("json")
+                .help("Print results as JSON Lines")
+                .long("json")
+                .conflicts_with_all(&[
+                    "count",
+                    "files_with_matches",
+                    "files_without_match",
+                    "quiet",
+                ])
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("text")
+                .help("Search binary files as if they were text")
+                .short("a")
+                .long("text")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("binary_without_match")
+                .help("Same as --binary-files=without-match")
+                .short("I")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("binary_files")
+                .value_name("TYPE")
+                .help("How to handle binary files")
+                .long("binary-files")
+                .possible_values(&["binary", "text", "without-match"])
+                .default_value("binary"),
+        )
+        .arg(
+            Arg::with_name("after_context")
+                .value_name("NUM")
+                .help("Print NUM lines of trailing context")
+                .short("A")
+                .long("after-context"),
+        )
+        .arg(
+            Arg::with_name("before_context")
+                .value_name("NUM")
+                .help("Print NUM lines of leading context")
+                .short("B")
+                .long("before-context"),
+        )
+        .arg(
+            Arg::with_name("context")
+                .value_name("NUM")
+                .help("Print NUM lines of leading and trailing context")
+                .short("C")
+                .long("context"),
+        )
+        .get_matches_from_safe(args)
+        .inspect_err(|e| {
+            // Help and version go to stdout with a successful exit
+            if !e.use_stderr() {
+                e.exit();
+            }
+        })?;
 
-    let pattern = matches.value_of("pattern").unwrap();
-    let pattern = RegexBuilder::new(pattern)
-        .case_insensitive(matches.is_present("insensitive"))
-        .build()
-        .map_err(|_| format!("Invalid pattern \"{}\"", pattern))?;
+    // With -e or -f, the first positional argument is really a file
+    let mut files = matches.values_of_lossy("files").unwrap();
+    let mut patterns = vec![];
+    if matches.is_present("regexp") || matches.is_present("pattern_file") {
+        if let Some(file) = matches.value_of("pattern") {
+            if matches.occurrences_of("files") == 0 {
+                files.clear();
+            }
+            files.insert(0, file.to_string());
+        }
+        for pattern in matches.values_of("regexp").into_iter().flatten() {
+            patterns.extend(pattern.split('\n').map(String::from));
+        }
+        for filename in matches.values_of("pattern_file").into_iter().flatten() {
+            let file = open(filename, false).map_err(|e| format!("{}: {}", filename, e))?;
+            for line in file.split(b'\n') {
+                patterns.push(String::from_utf8_lossy(&line?).into_owned());
+            }
+        }
+    } else {
+        let pattern = matches.value_of("pattern").unwrap();
+        patterns.extend(pattern.split('\n').map(String::from));
+    }
+    let mut syntax = if matches.is_present("fixed_strings") {
+        Syntax::Fixed
+    } else if matches.is_present("perl_regexp") {
+        let limit = matches.value_of("backtrack_limit").unwrap();
+        Syntax::Perl(
+            limit
+                .parse()
+                .map_err(|_| format!("Invalid backtrack limit \"{}\"", limit))?,
+        )
+    } else {
+        Syntax::Regex
+    };
+    // Capture groups need a regex, so spell fixed strings out as one
+    let replace = matches.value_of("replace").map(String::from);
+    if replace.is_some() && syntax == Syntax::Fixed {
+        patterns = patterns.iter().map(|p| regex::escape(p)).collect();
+        syntax = Syntax::Regex;
+    }
+    let insensitive = matches.is_present("insensitive")
+        || (matches.is_present("smart_case") && !patterns.iter().any(|p| has_uppercase(p, syntax)));
+    let matcher = Matcher::new(
+        &patterns,
+        syntax,
+        insensitive,
+        matches.is_present("word_regexp"),
+        matches.is_present("line_regexp"),
+        !matches.is_present("no_unicode"),
+    )?;
+
+    let context = |name: &str| -> MyResult<Option<usize>> {
+        match matches.value_of(name) {
+            Some(val) => val
+                .parse()
+                .map(Some)
+                .map_err(|_| From::from(format!("Invalid context length \"{}\"", val))),
+            None => Ok(None),
+        }
+    };
+    let both = context("context")?.unwrap_or(0);
+    let max_count = matches
+        .value_of("max_count")
+        .map(|val| {
+            val.parse()
+                .map_err(|_| format!("Invalid max count \"{}\"", val))
+        })
+        .transpose()?;
+    let max_depth = matches
+        .value_of("max_depth")
+        .map(|val| {
+            val.parse()
+                .map_err(|_| format!("Invalid max depth \"{}\"", val))
+        })
+        .transpose()?;
+    let threads = match matches.value_of("threads") {
+        Some(val) => val
+            .parse::<NonZeroUsize>()
+            .map_err(|_| format!("Invalid number of threads \"{}\"", val))?,
+        None => thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+    };
+
+    let mut globs = OverrideBuilder::new(".");
+    for glob in matches.values_of("include").into_iter().flatten() {
+        globs
+            .add(glob)
+            .map_err(|_| format!("Invalid --include \"{}\"", glob))?;
+    }
+    for glob in matches.values_of("exclude").into_iter().flatten() {
+        globs
+            .add(&format!("!{}", glob))
+            .map_err(|_| format!("Invalid --exclude \"{}\"", glob))?;
+    }
+
+    let only_matching = matches.is_present("only_matching");
+    let color = match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ if matches.is_present("color") => io::stdout().is_terminal(),
+        _ => false,
+    };
+    let colors = if color {
+        Colors::parse(&env::var("GREP_COLORS").unwrap_or_default())
+    } else {
+        Colors::default()
+    };
 
     Ok(Config {
-        pattern,
-        files: matches.values_of_lossy("files").unwrap(),
+        matcher,
+        files,
         recursive: matches.is_present("recursive"),
+        hidden: matches.is_present("hidden"),
+        no_ignore: matches.is_present("no_ignore"),
+        globs: globs.build()?,
+        max_depth,
+        threads: threads.get(),
+        search_zip: matches.is_present("search_zip"),
+        search_archives: matches.is_present("search_archives"),
         count: matches.is_present("count"),
         invert_match: matches.is_present("invert"),
+        line_number: matches.is_present("line_number"),
+        byte_offset: matches.is_present("byte_offset"),
+        with_filename: if matches.is_present("with_filename") {
+            Some(true)
+        } else if matches.is_present("no_filename") {
+            Some(false)
+        } else {
+            None
+        },
+        label: matches.value_of("label").unwrap().to_string(),
+        only_matching,
+        replace,
+        write: matches.is_present("write"),
+        dry_run: matches.is_present("dry_run"),
+        colors,
+        max_count,
+        files_with_matches: matches.is_present("files_with_matches"),
+        files_without_match: matches.is_present("files_without_match"),
+        quiet: matches.is_present("quiet"),
+        json: matches.is_present("json"),
+        binary: if matches.is_present("text") {
+            BinaryFiles::Text
+        } else if matches.is_present("binary_without_match") {
+            BinaryFiles::WithoutMatch
+        } else {
+            match matches.value_of("binary_files") {
+                Some("text") => BinaryFiles::Text,
+                Some("without-match") => BinaryFiles::WithoutMatch,
+                _ => BinaryFiles::Binary,
+            }
+        },
+        // Context lines are never shown with --only-matching
+        before_context: match only_matching {
+            true => 0,
+            false => context("before_context")?.unwrap_or(both),
+        },
+        after_context: match only_matching {
+            true => 0,
+            false => context("after_context")?.unwrap_or(both),
+        },
     })
 }
 
-pub fn run(config: Config) -> MyResult<()> {
-    let entries = find_files(&config.files, config.recursive);
-    let num_files = entries.len();
-    let print = |fname: &str, val: &str| {
-        if num_files > 1 {
-            print!("{}:{}", fname, val);
-        } else {
-            print!("{}", val);
-        }
-    };
+/// Search all the inputs and return grep's exit status: 0 if any line was
+/// selected, 1 if none was, and 2 if there was an error
+pub fn run(config: Config) -> MyResult<i32> {
+    let with_filename = config.with_filename.unwrap_or_else(|| {
+        config.files.len() > 1
+            || (config.recursive && config.files.iter().any(|f| Path::new(f).is_dir()))
+    });
+    // JSON messages carry their own line numbers, so they need no separators
+    let use_context = !config.json && (config.before_context > 0 || config.after_context > 0);
+    let start = Instant::now();
+    let mut totals = Stats::default();
+    let mut out = BufWriter::new(io::stdout());
+    let mut found = false;
+    let mut errors = false;
+    let mut printed = false;
+
+    // The walker hands paths to a pool of workers, and the printing thread
+    // takes each file's output in the order the walker found them. Files
+    // behind the one being printed can only get a few chunks ahead.
+    let done = AtomicBool::new(false);
+    let (path_tx, path_rx) = mpsc::channel::<(String, SyncSender<Piece>)>();
+    let path_rx = Mutex::new(path_rx);
+    let (config, done, path_rx) = (&config, &done, &path_rx);
+
+    thread::scope(|scope| {
+        let (file_tx, file_rx) = mpsc::channel::<Receiver<Piece>>();
+        scope.spawn(move || {
+            for entry in find_files(config) {
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                let (tx, rx) = mpsc::sync_channel(QUEUED_CHUNKS);
+                if file_tx.send(rx).is_err() {
+                    break;
+                }
+                let sent = match entry {
+                    Ok(filename) => path_tx.send((filename, tx)).is_ok(),
+                    Err(e) => tx.send(Piece::Done(Err(e.to_string()))).is_ok(),
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
 
-    for entry in entries {
-        match entry {
-            Err(e) => eprintln!("{}", e),
-            Ok(filename) => match open(&filename) {
-                Err(e) => eprintln!("{}: {}", filename, e),
-                Ok(file) => match find_lines(file, &config.pattern, config.invert_match) {
-                    Err(e) => eprintln!("{}", e),
-                    Ok(matches) => {
-                        if config.count {
-                            print(&filename, &format!("{}\n", matches.len()));
-                        } else {
-                            for line in &matches {
-                                print(&filename, line);
-                            }
+        for _ in 0..config.threads {
+            scope.spawn(move || loop {
+                let next = path_rx.lock().unwrap().recv();
+                match next {
+                    Ok((filename, tx)) if !done.load(Ordering::Relaxed) => {
+                        let mut output = Output::new(tx.clone(), filename == "-");
+                        let result = search(config, &filename, with_filename, &mut output)
+                            .map_err(|e| format!("{}: {}", filename, e));
+                        // Whatever was found before an error still gets printed
+                        if output.flush().is_err() || tx.send(Piece::Done(result)).is_err() {
+                            break;
                         }
                     }
-                },
-            },
+                    _ => break,
+                }
+            });
         }
-    }
-    Ok(())
-}
 
-fn find_files(files: &[String], recursive: bool) -> Vec<MyResult<String>> {
-    let mut results = vec![];
-    for path in files {
-        match path.as_str() {
-            "-" => results.push(Ok(path.to_string())),
-            _ => match fs::metadata(&path) {
-                Ok(metadata) => {
-                    if metadata.is_dir() {
-                        if recursive {
-                            for entry in WalkDir::new(path)
-                                .into_iter()
-                                .filter_map(|e| e.ok())
-                                .filter(|e| e.file_type().is_file())
-                            {
-                                results.push(Ok(entry.path().display().to_string()));
-                            }
-                        } else {
-                            results.push(Err(From::from(format!("{} is a directory", path))));
+        for rx in file_rx {
+            let mut started = false;
+            loop {
+                // Show what's ready before waiting on a slow input
+                let piece = match rx.try_recv() {
+                    Err(TryRecvError::Empty) => {
+                        out.flush()?;
+                        rx.recv()
+                    }
+                    piece => piece.map_err(|_| mpsc::RecvError),
+                };
+                match piece {
+                    Ok(Piece::Output(chunk)) => {
+                        // Separate groups from the previous file, too
+                        if use_context && printed && !started {
+                            paint(&mut out, &config.colors.separator, "--")?;
+                            writeln!(out)?;
+                        }
+                        started = true;
+                        printed = true;
+                        out.write_all(&chunk)?;
+                    }
+                    Ok(Piece::Done(Err(e))) => {
+                        out.flush()?;
+                        eprintln!("{}", e);
+                        errors = true;
+                        break;
+                    }
+                    Ok(Piece::Done(Ok(searched))) => {
+                        found |= searched.count > 0;
+                        totals.add(&searched.stats);
+                        // Nothing else can change the outcome
+                        if config.quiet && found {
+                            done.store(true, Ordering::Relaxed);
+                            return Ok(0);
                         }
-                    } else if metadata.is_file() {
-                        results.push(Ok(path.to_string()));
+                        break;
                     }
+                    Err(_) => break,
                 }
-                Err(e) => results.push(Err(From::from(format!("{}: {}", path, e)))),
-            },
+            }
         }
-    }
+        if config.json {
+            let summary = json!({
+                "type": "summary",
+                "data": {
+                    "elapsed_total": elapsed(start.elapsed()),
+                    "stats": totals.to_json(),
+                },
+            });
+            serde_json::to_writer(&mut out, &summary)?;
+            writeln!(out)?;
+        }
+        out.flush()?;
 
-    results
+        Ok(match (errors, found) {
+            (true, _) => 2,
+            (false, true) => 0,
+            (false, false) => 1,
+        })
+    })
 }
 
-fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
-    match filename {
-        "-" => Ok(Box::new(BufReader::new(io::stdin()))),
-        _ => Ok(Box::new(BufReader::new(File::open(filename)?))),
+fn search(
+    config: &Config,
+    filename: &str,
+    with_filename: bool,
+    out: &mut Output,
+) -> MyResult<Searched> {
+    if config.write || config.dry_run {
+        return rewrite(config, filename, with_filename, out);
+    }
+    if config.search_archives && is_archive(filename) {
+        return search_archive(config, filename, out);
     }
+    let file = open(filename, config.search_zip)?;
+    let name = if filename == "-" {
+        &config.label
+    } else {
+        filename
+    };
+    let mut printer = Printer::new(out, config, with_filename);
+    let mut stats = Stats::default();
+    let count = search_file(file, name, config, &mut printer, &mut stats)?;
+    Ok(Searched { count, stats })
 }
 
-fn find_lines<T: BufRead>(
-    mut file: T,
-    pattern: &Regex,
-    invert_match: bool,
-) -> MyResult<Vec<String>> {
-    let mut matches = vec![];
-    let mut line = String::new();
-
-    loop {
-        let bytes = file.read_line(&mut line)?;
-        if bytes == 0 {
+/// Search every regular file in a tarball, naming each one "archive!member"
+fn search_archive(config: &Config, filename: &str, out: &mut Output) -> MyResult<Searched> {
+    // An archive holds many files, so name them unless told not to
+    let with_filename = config.with_filename.unwrap_or(true);
+    let use_context = !config.json && (config.before_context > 0 || config.after_context > 0);
+    let mut searched = Searched {
+        count: 0,
+        stats: Stats::default(),
+    };
+    let mut printer = Printer::new(out, config, with_filename);
+    let mut archive = tar::Archive::new(open(filename, true)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = format!("{}!{}", filename, entry.path()?.display());
+        searched.count += search_file(
+            BufReader::new(entry),
+            &name,
+            config,
+            &mut printer,
+            &mut searched.stats,
+        )?;
+        if use_context && printer.out.written && printer.out.separator.is_none() {
+            let mut separator = vec![];
+            paint(&mut separator, &config.colors.separator, "--")?;
+            writeln!(separator)?;
+            printer.out.separator = Some(separator);
+        }
+        if config.quiet && searched.count > 0 {
             break;
         }
-        if (pattern.is_match(&line) && !invert_match) || (!pattern.is_match(&line) && invert_match)
-        {
-            matches.push(line.clone());
+    }
+    Ok(searched)
+}
+
+/// Apply --replace to a file, printing a diff for --dry-run and otherwise
+/// saving the result along with the usual output
+fn rewrite(
+    config: &Config,
+    filename: &str,
+    with_filename: bool,
+    out: &mut Output,
+) -> MyResult<Searched> {
+    if filename == "-" && !config.dry_run {
+        return Err(From::from("Can't write to standard input"));
+    }
+    let name = if filename == "-" {
+        &config.label
+    } else {
+        filename
+    };
+    let mut content = vec![];
+    open(filename, false)?.read_to_end(&mut content)?;
+
+    // Binary files are never rewritten
+    let mut lines = vec![];
+    let mut binary = false;
+    find_lines(&content[..], config, |event: Event| {
+        match event {
+            Event::Match(line) => lines.push(line),
+            Event::Binary(_) => binary = true,
+            _ => {}
         }
-        line.clear();
+        Ok(!binary)
+    })?;
+    if binary {
+        lines.clear();
     }
 
-    Ok(matches)
-}
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    let count = lines.len();
+    let mut changes = vec![];
+    for line in lines {
+        let body = line.text.strip_suffix(b"\n").unwrap_or(&line.text);
+        let mut new = vec![];
+        let mut last = 0;
+        for (range, text) in config.matcher.replacements(body, replacement)? {
+            new.extend_from_slice(&body[last..range.start]);
+            new.extend_from_slice(&text);
+            last = range.end;
+        }
+        new.extend_from_slice(&line.text[last..]);
+        if new != line.text {
+            changes.push(Change { line, new });
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{find_files, find_lines};
-    use rand::{distributions::Alphanumeric, Rng};
-    use regex::{Regex, RegexBuilder};
-    use std::io::Cursor;
+    if config.dry_run {
+        out.write_all(&unified_diff(name, &content, &changes)?)?;
+        return Ok(Searched {
+            count,
+            stats: Stats::default(),
+        });
+    }
+    if !changes.is_empty() {
+        let mut new = vec![];
+        let mut last = 0;
+        for change in &changes {
+            new.extend_from_slice(&content[last..change.line.offset]);
+            new.extend_from_slice(&change.new);
+            last = change.line.offset + change.line.text.len();
+        }
+        new.extend_from_slice(&content[last..]);
+        save(filename, &new)?;
+    }
+    let mut printer = Printer::new(out, config, with_filename);
+    let mut stats = Stats::default();
+    let count = search_file(&content[..], name, config, &mut printer, &mut stats)?;
+    Ok(Searched { count, stats })
+}
 
-    #[test]
-    fn test_find_lines() {
-        let text = b"Lorem\nIpsum\r\nDOLOR";
+/// Swap in new contents all at once by renaming a finished copy over the
+/// file, which keeps its permissions
+fn save(filename: &str, text: &[u8]) -> MyResult<()> {
+    let path = fs::canonicalize(filename)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(text)?;
+    temp.as_file()
+        .set_permissions(fs::metadata(&path)?.permissions())?;
+    temp.persist(&path)?;
+    Ok(())
+}
 
-        // The pattern _or_ should match the one line, "Lorem"
-        let re1 = Regex::new("or").unwrap();
-        let matches = find_lines(Cursor::new(&text), &re1, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+/// Show the changes to one file as a unified diff with three lines of context
+fn unified_diff(name: &str, content: &[u8], changes: &[Change]) -> MyResult<Vec<u8>> {
+    const CONTEXT: usize = 3;
+    let mut out = vec![];
+    if changes.is_empty() {
+        return Ok(out);
+    }
+    let lines: Vec<&[u8]> = content.split_inclusive(|&b| b == b'\n').collect();
+    let count_lines = |text: &[u8]| text.split_inclusive(|&b| b == b'\n').count();
+    writeln!(out, "--- {}\n+++ {}", name, name)?;
 
-        // When inverted, the function should match the other two lines
-        let matches = find_lines(Cursor::new(&text), &re1, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+    // Changes close enough for their context to meet share a hunk
+    let mut shift = 0;
+    let mut rest = changes;
+    while !rest.is_empty() {
+        let len = 1 + rest
+            .windows(2)
+            .take_while(|pair| pair[1].line.num - pair[0].line.num <= 2 * CONTEXT + 1)
+            .count();
+        let (hunk, tail) = rest.split_at(len);
+        rest = tail;
 
-        // This regex will be case-insensitive
-        let re2 = RegexBuilder::new("or")
-            .case_insensitive(true)
-            .build()
-            .unwrap();
+        let first = hunk[0].line.num.saturating_sub(CONTEXT).max(1);
+        let last = (hunk[len - 1].line.num + CONTEXT).min(lines.len());
+        let old_len = last - first + 1;
+        let new_len = old_len + hunk.iter().map(|c| count_lines(&c.new)).sum::<usize>() - len;
+        writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            first,
+            old_len,
+            (first as isize + shift) as usize,
+            new_len
+        )?;
+        shift += new_len as isize - old_len as isize;
 
-        // The two lines "Lorem" and "DOLOR" should match
-        let matches = find_lines(Cursor::new(&text), &re2, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+        // Runs of changed lines show all their removals before their additions
+        let mut added = vec![];
+        for num in first..=last {
+            match hunk.iter().find(|c| c.line.num == num) {
+                Some(change) => {
+                    diff_lines(&mut out, "-", &change.line.text)?;
+                    added.extend_from_slice(&change.new);
+                }
+                None => {
+                    diff_lines(&mut out, "+", &added)?;
+                    added.clear();
+                    diff_lines(&mut out, " ", lines[num - 1])?;
+                }
+            }
+        }
+        diff_lines(&mut out, "+", &added)?;
+    }
+    Ok(out)
+}
 
-        // When inverted, the one remaining line should match
-        let matches = find_lines(Cursor::new(&text), &re2, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+fn diff_lines(out: &mut Vec<u8>, sign: &str, text: &[u8]) -> io::Result<()> {
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        out.write_all(sign.as_bytes())?;
+        out.write_all(line)?;
+        if !line.ends_with(b"\n") {
+            out.write_all(b"\n\\ No newline at end of file\n")?;
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_find_files() {
-        // Verify that the function finds a file known to exist
-        let files = find_files(&["./tests/inputs/fox.txt".to_string()], false);
-        assert_eq!(files.len(), 1);
-        assert_eq!(files[0].as_ref().unwrap(), "./tests/inputs/fox.txt");
+fn search_file(
+    file: impl BufRead,
+    name: &str,
+    config: &Config,
+    printer: &mut Printer<impl Write>,
+    stats: &mut Stats,
+) -> MyResult<usize> {
+    let is_match = |event: &Event| matches!(event, Event::Match(_) | Event::Binary(_));
+    let count = if config.json {
+        let start = Instant::now();
+        let mut file = Counted {
+            inner: file,
+            bytes: 0,
+        };
+        let mut json = JsonPrinter::new(&mut printer.out, config, name);
+        // Keep going past a binary match so the stats count every line
+        let count = find_lines(&mut file, config, |event: Event| {
+            json.event(event).map(|_| true)
+        })?;
+        json.stats.elapsed = start.elapsed();
+        json.stats.bytes_searched = file.bytes;
+        json.stats.matched_lines = count;
+        json.end()?;
+        stats.add(&json.stats);
+        count
+    } else if config.quiet {
+        find_lines(file, config, |event: Event| Ok(!is_match(&event)))?
+    } else if config.files_with_matches || config.files_without_match {
+        let count = find_lines(file, config, |event: Event| Ok(!is_match(&event)))?;
+        if (count > 0) == config.files_with_matches {
+            printer.file_name(name)?;
+        }
+        count
+    } else if config.count {
+        let count = find_lines(file, config, |_: Event| Ok(true))?;
+        printer.count(name, count)?;
+        count
+    } else {
+        find_lines(file, config, |event: Event| {
+            let more = !matches!(event, Event::Binary(_));
+            printer.event(name, event).map(|_| more)
+        })?
+    };
+    Ok(count)
+}
 
-        // The function should reject a directory without the recursive option
-        let files = find_files(&["./tests/inputs".to_string()], false);
-        assert_eq!(files.len(), 1);
-        if let Err(e) = &files[0] {
-            assert_eq!(e.to_string(), "./tests/inputs is a directory");
+impl Matcher {
+    fn new(
+        patterns: &[String],
+        syntax: Syntax,
+        insensitive: bool,
+        word: bool,
+        line: bool,
+        unicode: bool,
+    ) -> MyResult<Matcher> {
+        // Aho-Corasick only folds ASCII case and knows nothing of boundaries,
+        // and it also handles an empty list of patterns, which never matches.
+        // Even ASCII patterns need Unicode folding, as "k" matches the Kelvin sign.
+        let fixed = syntax == Syntax::Fixed;
+        if patterns.is_empty() || (fixed && !word && !line && (!insensitive || !unicode)) {
+            return Ok(Matcher::Literal(
+                AhoCorasick::builder()
+                    .match_kind(MatchKind::LeftmostLongest)
+                    .ascii_case_insensitive(insensitive)
+                    .build(patterns)?,
+            ));
         }
 
-        // Verify the function recurses to find four files in the directory
-        let res = find_files(&["./tests/inputs".to_string()], true);
-        let mut files: Vec<String> = res
-            .iter()
-            .map(|r| r.as_ref().unwrap().replace("\\", "/"))
-            .collect();
-        files.sort();
-        assert_eq!(files.len(), 4);
-        assert_eq!(
-            files,
+        // Joined into one regex, the patterns would share a single numbering
+        // of their groups, which breaks backreferences and --replace
+        if patterns.len() > 1 && patterns.iter().any(|p| has_groups(p, syntax, unicode)) {
+            return patterns
+                .iter()
+                .map(|p| Matcher::new(slice::from_ref(p), syntax, insensitive, word, line, unicode))
+                .collect::<MyResult<_>>()
+                .map(Matcher::Any);
+        }
+
+        let mut alternates = vec![];
+        for pattern in patterns {
+            match syntax {
+                Syntax::Fixed if insensitive && unicode => alternates.push(fold_literal(pattern)),
+                Syntax::Fixed => alternates.push(regex::escape(pattern)),
+                Syntax::Regex => {
+                    RegexBuilder::new(pattern)
+                        .unicode(unicode)
+                        .build()
+                        .map_err(|_| invalid_pattern(pattern))?;
+                    alternates.push(format!("(?:{})", pattern));
+                }
+                Syntax::Perl(_) => alternates.push(format!("(?:{})", pattern)),
+            }
+        }
+        let pattern = alternates.join("|");
+        let pattern = match (word, line) {
+            (_, true) => format!("^(?:{})$", pattern),
+            // Like grep, a word only needs no word character on either side,
+            // so needles that start or end with punctuation still work
+            (true, false) if matches!(syntax, Syntax::Perl(_)) => {
+                format!(r"(?<!\w)(?:{})(?!\w)", pattern)
+            }
+            (true, false) => format!(r"\b{{start-half}}(?:{})\b{{end-half}}", pattern),
+            _ => pattern,
+        };
+
+        if let Syntax::Perl(limit) = syntax {
+            return Matcher::fancy(patterns, &pattern, insensitive, limit);
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(insensitive)
+            .unicode(unicode)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+        Ok(Matcher::Regex(regex))
+    }
+
+    #[cfg(feature = "pcre")]
+    fn fancy(
+        patterns: &[String],
+        pattern: &str,
+        insensitive: bool,
+        limit: usize,
+    ) -> MyResult<Matcher> {
+        // Check each pattern alone so the error names the one at fault
+        for pattern in patterns {
+            fancy_regex::Regex::new(pattern)
+                .map_err(|e| format!("Invalid pattern \"{}\": {}", pattern, e))?;
+        }
+        let regex = fancy_regex::RegexBuilder::new(pattern)
+            .case_insensitive(insensitive)
+            .backtrack_limit(limit)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+        Ok(Matcher::Fancy(regex))
+    }
+
+    #[cfg(not(feature = "pcre"))]
+    fn fancy(_: &[String], _: &str, _: bool, _: usize) -> MyResult<Matcher> {
+        Err(From::from(
+            "-P is not supported: grepr was built without the \"pcre\" feature",
+        ))
+    }
+
+    fn is_match(&self, text: &[u8]) -> MyResult<bool> {
+        match self {
+            Matcher::Regex(regex) => Ok(regex.is_match(text)),
+            Matcher::Literal(ac) => Ok(ac.is_match(text)),
+            #[cfg(feature = "pcre")]
+            Matcher::Fancy(regex) => {
+                for chunk in text.utf8_chunks() {
+                    if regex
+                        .is_match(chunk.valid())
+                        .map_err(|e| format!("-P search failed: {}", e))?
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Matcher::Any(matchers) => {
+                for matcher in matchers {
+                    if matcher.is_match(text)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn find_iter(&self, text: &[u8]) -> MyResult<Vec<Range<usize>>> {
+        match self {
+            Matcher::Regex(regex) => Ok(regex.find_iter(text).map(|m| m.range()).collect()),
+            Matcher::Literal(ac) => Ok(ac.find_iter(text).map(|m| m.range()).collect()),
+            #[cfg(feature = "pcre")]
+            Matcher::Fancy(regex) => {
+                // The backtracking engine needs text, so search each run of
+                // valid UTF-8 on its own
+                let mut ranges = vec![];
+                let mut start = 0;
+                for chunk in text.utf8_chunks() {
+                    for m in regex.find_iter(chunk.valid()) {
+                        let m = m.map_err(|e| format!("-P search failed: {}", e))?;
+                        ranges.push(start + m.start()..start + m.end());
+                    }
+                    start += chunk.valid().len() + chunk.invalid().len();
+                }
+                Ok(ranges)
+            }
+            Matcher::Any(_) => Ok(self
+                .each_match(text, None)?
+                .into_iter()
+                .map(|(range, _)| range)
+                .collect()),
+        }
+    }
+
+    /// Each match along with the replacement to put in its place, with
+    /// $1, ${name} and the like filled in from its capture groups
+    fn replacements(
+        &self,
+        text: &[u8],
+        replacement: &str,
+    ) -> MyResult<Vec<(Range<usize>, Vec<u8>)>> {
+        match self {
+            Matcher::Regex(regex) => Ok(regex
+                .captures_iter(text)
+                .map(|caps| {
+                    let mut dst = vec![];
+                    caps.expand(replacement.as_bytes(), &mut dst);
+                    (caps.get(0).unwrap().range(), dst)
+                })
+                .collect()),
+            Matcher::Literal(ac) => Ok(ac
+                .find_iter(text)
+                .map(|m| (m.range(), replacement.as_bytes().to_vec()))
+                .collect()),
+            #[cfg(feature = "pcre")]
+            Matcher::Fancy(regex) => {
+                let mut found = vec![];
+                let mut start = 0;
+                for chunk in text.utf8_chunks() {
+                    for caps in regex.captures_iter(chunk.valid()) {
+                        let caps = caps.map_err(|e| format!("-P search failed: {}", e))?;
+                        let mut dst = String::new();
+                        caps.expand(replacement, &mut dst);
+                        let m = caps.get(0).unwrap();
+                        found.push((start + m.start()..start + m.end(), dst.into_bytes()));
+                    }
+                    start += chunk.valid().len() + chunk.invalid().len();
+                }
+                Ok(found)
+            }
+            Matcher::Any(_) => self.each_match(text, Some(replacement)),
+        }
+    }
+
+    /// Every match in turn, found one search at a time
+    fn each_match(
+        &self,
+        text: &[u8],
+        replacement: Option<&str>,
+    ) -> MyResult<Vec<(Range<usize>, Vec<u8>)>> {
+        let mut found = vec![];
+        let mut start = 0;
+        while start <= text.len() {
+            let (range, dst) = match self.next_match(text, start, replacement)? {
+                Some(next) => next,
+                None => break,
+            };
+            start = range.end;
+            if range.is_empty() {
+                // Step over an empty match to the next character
+                start += 1;
+                while start < text.len() && text[start] & 0xC0 == 0x80 {
+                    start += 1;
+                }
+            }
+            found.push((range, dst));
+        }
+        Ok(found)
+    }
+
+    /// The first match that starts at or after `start`, with the text to put
+    /// in its place when given a replacement
+    fn next_match(
+        &self,
+        text: &[u8],
+        start: usize,
+        replacement: Option<&str>,
+    ) -> MyResult<Option<(Range<usize>, Vec<u8>)>> {
+        match self {
+            Matcher::Regex(regex) => Ok(match replacement {
+                Some(replacement) => regex.captures_at(text, start).map(|caps| {
+                    let mut dst = vec![];
+                    caps.expand(replacement.as_bytes(), &mut dst);
+                    (caps.get(0).unwrap().range(), dst)
+                }),
+                None => regex.find_at(text, start).map(|m| (m.range(), vec![])),
+            }),
+            Matcher::Literal(ac) => Ok(ac
+                .find(aho_corasick::Input::new(text).span(start..text.len()))
+                .map(|m| {
+                    (
+                        m.range(),
+                        replacement.unwrap_or_default().as_bytes().to_vec(),
+                    )
+                })),
+            #[cfg(feature = "pcre")]
+            Matcher::Fancy(regex) => {
+                let mut offset = 0;
+                for chunk in text.utf8_chunks() {
+                    let valid = chunk.valid();
+                    if start <= offset + valid.len() {
+                        let pos = start.saturating_sub(offset);
+                        let found = match replacement {
+                            Some(replacement) => regex.captures_from_pos(valid, pos).map(|caps| {
+                                caps.map(|caps| {
+                                    let mut dst = String::new();
+                                    caps.expand(replacement, &mut dst);
+                                    (caps.get(0).unwrap().range(), dst.into_bytes())
+                                })
+                            }),
+                            None => regex
+                                .find_from_pos(valid, pos)
+                                .map(|m| m.map(|m| (m.range(), vec![]))),
+                        };
+                        let found = found.map_err(|e| format!("-P search failed: {}", e))?;
+                        if let Some((range, dst)) = found {
+                            return Ok(Some((offset + range.start..offset + range.end, dst)));
+                        }
+                    }
+                    offset += valid.len() + chunk.invalid().len();
+                }
+                Ok(None)
+            }
+            Matcher::Any(matchers) => {
+                // Ties go to the earlier pattern, as with alternation
+                let mut best: Option<(Range<usize>, Vec<u8>)> = None;
+                for matcher in matchers {
+                    if let Some(found) = matcher.next_match(text, start, replacement)? {
+                        if best
+                            .as_ref()
+                            .is_none_or(|(range, _)| found.0.start < range.start)
+                        {
+                            best = Some(found);
+                        }
+                    }
+                }
+                Ok(best)
+            }
+        }
+    }
+}
+
+/// Whether a pattern has capture groups of its own
+fn has_groups(pattern: &str, syntax: Syntax, unicode: bool) -> bool {
+    match syntax {
+        Syntax::Fixed => false,
+        Syntax::Regex => RegexBuilder::new(pattern)
+            .unicode(unicode)
+            .build()
+            .is_ok_and(|regex| regex.captures_len() > 1),
+        #[cfg(feature = "pcre")]
+        Syntax::Perl(_) => {
+            fancy_regex::Regex::new(pattern).is_ok_and(|regex| regex.captures_len() > 1)
+        }
+        #[cfg(not(feature = "pcre"))]
+        Syntax::Perl(_) => false,
+    }
+}
+
+/// Escapes a -F needle for a case-insensitive regex with full case folding.
+/// The regex engine folds one character to one, so wherever the needle
+/// spells out a longer folding, such as "ss", the characters that fold to
+/// it (ß and ẞ) are accepted as well. Overlapping runs pair off from the left.
+fn fold_literal(needle: &str) -> String {
+    let folded: Vec<char> = needle
+        .chars()
+        .flat_map(|c| match FULL_FOLDS.iter().find(|(from, _)| *from == c) {
+            Some((_, to)) => to.chars().collect(),
+            None => vec![c],
+        })
+        .collect();
+
+    let mut pattern = String::new();
+    let mut i = 0;
+    while i < folded.len() {
+        // Take the longest folding spelled out here, so "ffi" also
+        // accepts ﬃ, ﬀi and fﬁ
+        let rest = &folded[i..];
+        let len = foldings(rest).map(|(_, to)| to.chars().count()).max();
+        match len {
+            Some(len) => {
+                pattern += &format!("(?:{})", spellings(&rest[..len]).join("|"));
+                i += len;
+            }
+            None => {
+                pattern += &regex::escape(&rest[0].to_string());
+                i += 1;
+            }
+        }
+    }
+    pattern
+}
+
+/// The foldings that the start of a folded needle spells out
+fn foldings(folded: &[char]) -> impl Iterator<Item = &'static (char, &'static str)> + '_ {
+    FULL_FOLDS.iter().filter(move |(_, to)| {
+        to.chars().count() <= folded.len() && to.chars().zip(folded).all(|(a, &b)| same_case(a, b))
+    })
+}
+
+/// Every way to write a folded span, as escaped regexes
+fn spellings(folded: &[char]) -> Vec<String> {
+    let Some((first, rest)) = folded.split_first() else {
+        return vec![String::new()];
+    };
+    let mut found: Vec<String> = spellings(rest)
+        .into_iter()
+        .map(|tail| regex::escape(&first.to_string()) + &tail)
+        .collect();
+    for (from, to) in foldings(folded) {
+        for tail in spellings(&folded[to.chars().count()..]) {
+            found.push(regex::escape(&from.to_string()) + &tail);
+        }
+    }
+    found
+}
+
+fn same_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase()) || a.to_uppercase().eq(b.to_uppercase())
+}
+
+// Characters whose full case folding is more than one character,
+// from CaseFolding.txt (status F)
+const FULL_FOLDS: &[(char, &str)] = &[
+    ('\u{df}', "ss"),
+    ('\u{130}', "i\u{307}"),
+    ('\u{149}', "\u{2bc}n"),
+    ('\u{1f0}', "j\u{30c}"),
+    ('\u{390}', "\u{3b9}\u{308}\u{301}"),
+    ('\u{3b0}', "\u{3c5}\u{308}\u{301}"),
+    ('\u{587}', "\u{565}\u{582}"),
+    ('\u{1e96}', "h\u{331}"),
+    ('\u{1e97}', "t\u{308}"),
+    ('\u{1e98}', "w\u{30a}"),
+    ('\u{1e99}', "y\u{30a}"),
+    ('\u{1e9a}', "a\u{2be}"),
+    ('\u{1e9e}', "ss"),
+    ('\u{1f50}', "\u{3c5}\u{313}"),
+    ('\u{1f52}', "\u{3c5}\u{313}\u{300}"),
+    ('\u{1f54}', "\u{3c5}\u{313}\u{301}"),
+    ('\u{1f56}', "\u{3c5}\u{313}\u{342}"),
+    ('\u{1f80}', "\u{1f00}\u{3b9}"),
+    ('\u{1f81}', "\u{1f01}\u{3b9}"),
+    ('\u{1f82}', "\u{1f02}\u{3b9}"),
+    ('\u{1f83}', "\u{1f03}\u{3b9}"),
+    ('\u{1f84}', "\u{1f04}\u{3b9}"),
+    ('\u{1f85}', "\u{1f05}\u{3b9}"),
+    ('\u{1f86}', "\u{1f06}\u{3b9}"),
+    ('\u{1f87}', "\u{1f07}\u{3b9}"),
+    ('\u{1f88}', "\u{1f00}\u{3b9}"),
+    ('\u{1f89}', "\u{1f01}\u{3b9}"),
+    ('\u{1f8a}', "\u{1f02}\u{3b9}"),
+    ('\u{1f8b}', "\u{1f03}\u{3b9}"),
+    ('\u{1f8c}', "\u{1f04}\u{3b9}"),
+    ('\u{1f8d}', "\u{1f05}\u{3b9}"),
+    ('\u{1f8e}', "\u{1f06}\u{3b9}"),
+    ('\u{1f8f}', "\u{1f07}\u{3b9}"),
+    ('\u{1f90}', "\u{1f20}\u{3b9}"),
+    ('\u{1f91}', "\u{1f21}\u{3b9}"),
+    ('\u{1f92}', "\u{1f22}\u{3b9}"),
+    ('\u{1f93}', "\u{1f23}\u{3b9}"),
+    ('\u{1f94}', "\u{1f24}\u{3b9}"),
+    ('\u{1f95}', "\u{1f25}\u{3b9}"),
+    ('\u{1f96}', "\u{1f26}\u{3b9}"),
+    ('\u{1f97}', "\u{1f27}\u{3b9}"),
+    ('\u{1f98}', "\u{1f20}\u{3b9}"),
+    ('\u{1f99}', "\u{1f21}\u{3b9}"),
+    ('\u{1f9a}', "\u{1f22}\u{3b9}"),
+    ('\u{1f9b}', "\u{1f23}\u{3b9}"),
+    ('\u{1f9c}', "\u{1f24}\u{3b9}"),
+    ('\u{1f9d}', "\u{1f25}\u{3b9}"),
+    ('\u{1f9e}', "\u{1f26}\u{3b9}"),
+    ('\u{1f9f}', "\u{1f27}\u{3b9}"),
+    ('\u{1fa0}', "\u{1f60}\u{3b9}"),
+    ('\u{1fa1}', "\u{1f61}\u{3b9}"),
+    ('\u{1fa2}', "\u{1f62}\u{3b9}"),
+    ('\u{1fa3}', "\u{1f63}\u{3b9}"),
+    ('\u{1fa4}', "\u{1f64}\u{3b9}"),
+    ('\u{1fa5}', "\u{1f65}\u{3b9}"),
+    ('\u{1fa6}', "\u{1f66}\u{3b9}"),
+    ('\u{1fa7}', "\u{1f67}\u{3b9}"),
+    ('\u{1fa8}', "\u{1f60}\u{3b9}"),
+    ('\u{1fa9}', "\u{1f61}\u{3b9}"),
+    ('\u{1faa}', "\u{1f62}\u{3b9}"),
+    ('\u{1fab}', "\u{1f63}\u{3b9}"),
+    ('\u{1fac}', "\u{1f64}\u{3b9}"),
+    ('\u{1fad}', "\u{1f65}\u{3b9}"),
+    ('\u{1fae}', "\u{1f66}\u{3b9}"),
+    ('\u{1faf}', "\u{1f67}\u{3b9}"),
+    ('\u{1fb2}', "\u{1f70}\u{3b9}"),
+    ('\u{1fb3}', "\u{3b1}\u{3b9}"),
+    ('\u{1fb4}', "\u{3ac}\u{3b9}"),
+    ('\u{1fb6}', "\u{3b1}\u{342}"),
+    ('\u{1fb7}', "\u{3b1}\u{342}\u{3b9}"),
+    ('\u{1fbc}', "\u{3b1}\u{3b9}"),
+    ('\u{1fc2}', "\u{1f74}\u{3b9}"),
+    ('\u{1fc3}', "\u{3b7}\u{3b9}"),
+    ('\u{1fc4}', "\u{3ae}\u{3b9}"),
+    ('\u{1fc6}', "\u{3b7}\u{342}"),
+    ('\u{1fc7}', "\u{3b7}\u{342}\u{3b9}"),
+    ('\u{1fcc}', "\u{3b7}\u{3b9}"),
+    ('\u{1fd2}', "\u{3b9}\u{308}\u{300}"),
+    ('\u{1fd3}', "\u{3b9}\u{308}\u{301}"),
+    ('\u{1fd6}', "\u{3b9}\u{342}"),
+    ('\u{1fd7}', "\u{3b9}\u{308}\u{342}"),
+    ('\u{1fe2}', "\u{3c5}\u{308}\u{300}"),
+    ('\u{1fe3}', "\u{3c5}\u{308}\u{301}"),
+    ('\u{1fe4}', "\u{3c1}\u{313}"),
+    ('\u{1fe6}', "\u{3c5}\u{342}"),
+    ('\u{1fe7}', "\u{3c5}\u{308}\u{342}"),
+    ('\u{1ff2}', "\u{1f7c}\u{3b9}"),
+    ('\u{1ff3}', "\u{3c9}\u{3b9}"),
+    ('\u{1ff4}', "\u{3ce}\u{3b9}"),
+    ('\u{1ff6}', "\u{3c9}\u{342}"),
+    ('\u{1ff7}', "\u{3c9}\u{342}\u{3b9}"),
+    ('\u{1ffc}', "\u{3c9}\u{3b9}"),
+    ('\u{fb00}', "ff"),
+    ('\u{fb01}', "fi"),
+    ('\u{fb02}', "fl"),
+    ('\u{fb03}', "ffi"),
+    ('\u{fb04}', "ffl"),
+    ('\u{fb05}', "st"),
+    ('\u{fb06}', "st"),
+    ('\u{fb13}', "\u{574}\u{576}"),
+    ('\u{fb14}', "\u{574}\u{565}"),
+    ('\u{fb15}', "\u{574}\u{56b}"),
+    ('\u{fb16}', "\u{57e}\u{576}"),
+    ('\u{fb17}', "\u{574}\u{56d}"),
+];
+
+/// Whether a pattern asks for case to matter under --smart-case, not
+/// counting the letters in escapes like \S or \p{Lu}
+fn has_uppercase(pattern: &str, syntax: Syntax) -> bool {
+    if syntax == Syntax::Fixed {
+        return pattern.chars().any(char::is_uppercase);
+    }
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some('p' | 'P') = chars.next() {
+                    if chars.next() == Some('{') {
+                        chars.find(|&c| c == '}');
+                    }
+                }
+            }
+            c if c.is_uppercase() => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Point out when a pattern needs features only -P has
+fn invalid_pattern(pattern: &str) -> String {
+    let perl_only = ["(?=", "(?!", "(?<=", "(?<!", "(?>"]
+        .iter()
+        .any(|syntax| pattern.contains(syntax))
+        || Regex::new(r"\\[1-9]").unwrap().is_match(pattern.as_bytes());
+    if perl_only {
+        format!(
+            "Invalid pattern \"{}\": lookaround and backreferences need -P",
+            pattern
+        )
+    } else {
+        format!("Invalid pattern \"{}\"", pattern)
+    }
+}
+
+impl Compression {
+    fn detect(filename: &str) -> Option<Compression> {
+        match Path::new(filename).extension()?.to_str()? {
+            "gz" | "tgz" => Some(Compression::Gzip),
+            "zst" | "tzst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl Colors {
+    /// Start from grep's default colors and apply a GREP_COLORS spec
+    fn parse(spec: &str) -> Colors {
+        let mut colors = Colors {
+            selected_match: "01;31".to_string(),
+            context_match: "01;31".to_string(),
+            selected_line: String::new(),
+            context_line: String::new(),
+            file_name: "35".to_string(),
+            line_number: "32".to_string(),
+            byte_offset: "32".to_string(),
+            separator: "36".to_string(),
+        };
+        let valid = |val: &str| val.chars().all(|c| c.is_ascii_digit() || c == ';');
+        for (name, val) in spec
+            .split(':')
+            .filter_map(|cap| cap.split_once('='))
+            .filter(|(_, val)| valid(val))
+        {
+            let val = val.to_string();
+            match name {
+                "mt" => {
+                    colors.selected_match = val.clone();
+                    colors.context_match = val;
+                }
+                "ms" => colors.selected_match = val,
+                "mc" => colors.context_match = val,
+                "sl" => colors.selected_line = val,
+                "cx" => colors.context_line = val,
+                "fn" => colors.file_name = val,
+                "ln" => colors.line_number = val,
+                "bn" => colors.byte_offset = val,
+                "se" => colors.separator = val,
+                _ => {}
+            }
+        }
+        colors
+    }
+}
+
+fn paint(out: &mut impl Write, sgr: &str, text: impl AsRef<[u8]>) -> io::Result<()> {
+    let text = text.as_ref();
+    if sgr.is_empty() || text.is_empty() {
+        out.write_all(text)
+    } else {
+        write!(out, "\x1b[{}m\x1b[K", sgr)?;
+        out.write_all(text)?;
+        write!(out, "\x1b[m\x1b[K")
+    }
+}
+
+impl<W: Write> Printer<W> {
+    fn new(out: W, config: &Config, with_filename: bool) -> Self {
+        Printer {
+            out,
+            matcher: config.matcher.clone(),
+            invert_match: config.invert_match,
+            only_matching: config.only_matching,
+            replace: config.replace.clone(),
+            colors: config.colors.clone(),
+            with_filename,
+            line_number: config.line_number,
+            byte_offset: config.byte_offset,
+        }
+    }
+
+    fn event(&mut self, fname: &str, event: Event) -> MyResult<()> {
+        match event {
+            Event::Match(line) => self.line(fname, true, &line),
+            Event::Context(line) => self.line(fname, false, &line),
+            Event::Break => self.group_separator(),
+            Event::Binary(_) => Ok(writeln!(self.out, "Binary file {} matches", fname)?),
+        }
+    }
+
+    fn group_separator(&mut self) -> MyResult<()> {
+        paint(&mut self.out, &self.colors.separator, "--")?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn prefix(&mut self, fname: &str, sep: &str, num: usize, offset: usize) -> MyResult<()> {
+        if self.with_filename {
+            paint(&mut self.out, &self.colors.file_name, fname)?;
+            paint(&mut self.out, &self.colors.separator, sep)?;
+        }
+        if self.line_number {
+            paint(&mut self.out, &self.colors.line_number, num.to_string())?;
+            paint(&mut self.out, &self.colors.separator, sep)?;
+        }
+        if self.byte_offset {
+            paint(&mut self.out, &self.colors.byte_offset, offset.to_string())?;
+            paint(&mut self.out, &self.colors.separator, sep)?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, fname: &str, selected: bool, line: &Line) -> MyResult<()> {
+        let text = line.text.strip_suffix(b"\n").unwrap_or(&line.text);
+        // Each match, paired with what to show for it
+        let matches: Vec<_> = match &self.replace {
+            Some(replacement) => self.matcher.replacements(text, replacement)?,
+            None => self
+                .matcher
+                .find_iter(text)?
+                .into_iter()
+                .filter(|range| !range.is_empty())
+                .map(|range| (range.clone(), text[range].to_vec()))
+                .collect(),
+        };
+
+        if self.only_matching {
+            for (range, shown) in matches {
+                self.prefix(fname, ":", line.num, line.offset + range.start)?;
+                paint(&mut self.out, &self.colors.selected_match, shown)?;
+                writeln!(self.out)?;
+            }
+            return Ok(());
+        }
+
+        self.prefix(
+            fname,
+            if selected { ":" } else { "-" },
+            line.num,
+            line.offset,
+        )?;
+        let (match_color, line_color) = if selected {
+            (&self.colors.selected_match, &self.colors.selected_line)
+        } else {
+            (&self.colors.context_match, &self.colors.context_line)
+        };
+        // Only lines that really matched the pattern have anything to highlight
+        let mut last = 0;
+        if selected != self.invert_match && (self.replace.is_some() || !match_color.is_empty()) {
+            for (range, shown) in matches {
+                // Like grep, the line color is restarted before every segment
+                let before = &text[last..range.start];
+                if !line_color.is_empty() {
+                    write!(self.out, "\x1b[{}m\x1b[K", line_color)?;
+                }
+                self.out.write_all(before)?;
+                paint(&mut self.out, match_color, shown)?;
+                last = range.end;
+            }
+        }
+        paint(&mut self.out, line_color, &text[last..])?;
+        self.out.write_all(&line.text[text.len()..])?;
+        Ok(())
+    }
+
+    fn file_name(&mut self, fname: &str) -> MyResult<()> {
+        paint(&mut self.out, &self.colors.file_name, fname)?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn count(&mut self, fname: &str, count: usize) -> MyResult<()> {
+        if self.with_filename {
+            paint(&mut self.out, &self.colors.file_name, fname)?;
+            paint(&mut self.out, &self.colors.separator, ":")?;
+        }
+        writeln!(self.out, "{}", count)?;
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> JsonPrinter<'a, W> {
+    fn new(out: &'a mut W, config: &'a Config, path: &'a str) -> Self {
+        JsonPrinter {
+            out,
+            matcher: &config.matcher,
+            path,
+            begun: false,
+            binary_offset: None,
+            stats: Stats {
+                searches: 1,
+                ..Stats::default()
+            },
+        }
+    }
+
+    fn event(&mut self, event: Event) -> MyResult<()> {
+        match event {
+            Event::Match(line) => self.line("match", &line),
+            Event::Context(line) => self.line("context", &line),
+            Event::Break => Ok(()),
+            Event::Binary(offset) => {
+                self.binary_offset = Some(offset);
+                self.begin()
+            }
+        }
+    }
+
+    /// Files are only announced once they have something to show
+    fn begin(&mut self) -> MyResult<()> {
+        if !self.begun {
+            self.begun = true;
+            self.stats.searches_with_match = 1;
+            self.message("begin", json!({ "path": { "text": self.path } }))?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, kind: &str, line: &Line) -> MyResult<()> {
+        self.begin()?;
+        let body = line.text.strip_suffix(b"\n").unwrap_or(&line.text);
+        let submatches: Vec<Value> = self
+            .matcher
+            .find_iter(body)?
+            .into_iter()
+            .filter(|range| !range.is_empty())
+            .map(|range| {
+                json!({
+                    "match": data(&body[range.clone()]),
+                    "start": range.start,
+                    "end": range.end,
+                })
+            })
+            .collect();
+        if kind == "match" {
+            self.stats.matches += submatches.len();
+        }
+        self.message(
+            kind,
+            json!({
+                "path": { "text": self.path },
+                "lines": data(&line.text),
+                "line_number": line.num,
+                "absolute_offset": line.offset,
+                "submatches": submatches,
+            }),
+        )
+    }
+
+    fn end(&mut self) -> MyResult<()> {
+        if self.begun {
+            // The stats can't include the bytes of the message carrying them
+            let data = json!({
+                "path": { "text": self.path },
+                "binary_offset": self.binary_offset,
+                "stats": self.stats.to_json(),
+            });
+            self.message("end", data)?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, kind: &str, data: Value) -> MyResult<()> {
+        let mut message = serde_json::to_vec(&json!({ "type": kind, "data": data }))?;
+        message.push(b'\n');
+        self.out.write_all(&message)?;
+        self.stats.bytes_printed += message.len();
+        Ok(())
+    }
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.elapsed += other.elapsed;
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.bytes_searched += other.bytes_searched;
+        self.bytes_printed += other.bytes_printed;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "elapsed": elapsed(self.elapsed),
+            "searches": self.searches,
+            "searches_with_match": self.searches_with_match,
+            "bytes_searched": self.bytes_searched,
+            "bytes_printed": self.bytes_printed,
+            "matched_lines": self.matched_lines,
+            "matches": self.matches,
+        })
+    }
+}
+
+fn elapsed(duration: Duration) -> Value {
+    json!({
+        "secs": duration.as_secs(),
+        "nanos": duration.subsec_nanos(),
+        "human": format!("{:.6}s", duration.as_secs_f64()),
+    })
+}
+
+/// Text that isn't UTF-8 goes out as base64 bytes instead
+fn data(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": STANDARD.encode(bytes) }),
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counted<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes += amt;
+        self.inner.consume(amt);
+    }
+}
+
+/// How much output a worker gathers before handing it on
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks a file waiting its turn may hold before its worker stops
+const QUEUED_CHUNKS: usize = 4;
+
+impl Output {
+    fn new(tx: SyncSender<Piece>, eager: bool) -> Self {
+        Output {
+            tx,
+            buf: vec![],
+            eager,
+            separator: None,
+            written: false,
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if let Some(separator) = self.separator.take() {
+            self.buf.extend_from_slice(&separator);
+        }
+        self.buf.extend_from_slice(data);
+        self.written = true;
+        if self.buf.len() >= CHUNK_SIZE || (self.eager && data.ends_with(b"\n")) {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = std::mem::take(&mut self.buf);
+            // The printing thread only hangs up once it has stopped for good
+            self.tx
+                .send(Piece::Output(chunk))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a defaults file of one argument per line, skipping blank lines and
+/// comments
+fn read_defaults(path: &Path) -> MyResult<Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+fn find_files(config: &Config) -> impl Iterator<Item = MyResult<String>> + '_ {
+    config
+        .files
+        .iter()
+        .flat_map(move |path| -> Box<dyn Iterator<Item = MyResult<String>>> {
+            let path = path.to_string();
+            if path == "-" {
+                return Box::new(iter::once(Ok(path)));
+            }
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_dir() && config.recursive => {
+                    Box::new(walk(&path, config))
+                }
+                Ok(metadata) if metadata.is_dir() => Box::new(iter::once(Err(From::from(
+                    format!("{} is a directory", path),
+                )))),
+                Ok(metadata) if metadata.is_file() => Box::new(iter::once(Ok(path))),
+                Ok(_) => Box::new(iter::empty()),
+                Err(e) => Box::new(iter::once(Err(From::from(format!("{}: {}", path, e))))),
+            }
+        })
+}
+
+fn walk(dir: &str, config: &Config) -> impl Iterator<Item = MyResult<String>> {
+    // Hidden files are checked here so that --include can't bring them back
+    let hidden = config.hidden;
+    let skip = move |name: &str| name == ".git" || (!hidden && name.starts_with('.'));
+
+    // Sorting keeps the output the same from run to run
+    WalkBuilder::new(dir)
+        .standard_filters(!config.no_ignore)
+        .hidden(false)
+        .require_git(false)
+        .overrides(config.globs.clone())
+        .max_depth(config.max_depth)
+        .filter_entry(move |entry| {
+            entry.depth() == 0 || !skip(&entry.file_name().to_string_lossy())
+        })
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build()
+        .filter_map(|entry| match entry {
+            Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                Some(Ok(entry.path().display().to_string()))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(From::from(e.to_string()))),
+        })
+}
+
+fn open(filename: &str, decompress: bool) -> MyResult<Box<dyn BufRead>> {
+    let file: Box<dyn Read> = match filename {
+        "-" => Box::new(io::stdin()),
+        _ => Box::new(File::open(filename)?),
+    };
+    let file: Box<dyn Read> = match Compression::detect(filename).filter(|_| decompress) {
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(file)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::new(file)?),
+        None => file,
+    };
+    Ok(Box::new(BufReader::new(file)))
+}
+
+fn is_archive(filename: &str) -> bool {
+    [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst"]
+        .iter()
+        .any(|ext| filename.ends_with(ext))
+}
+
+fn find_lines<T: BufRead>(mut file: T, config: &Config, mut sink: impl Sink) -> MyResult<usize> {
+    let mut num_matches = 0;
+    let mut line_num = 0;
+    let mut offset = 0;
+    let mut last_emitted = 0;
+    let mut after_left = 0;
+    let mut before: VecDeque<Line> = VecDeque::with_capacity(config.before_context);
+    let use_context = config.before_context > 0 || config.after_context > 0;
+    let mut text = vec![];
+
+    // Sniff the first block for a NUL, and keep watching every later line
+    let check_binary = config.binary != BinaryFiles::Text;
+    let mut nul = match check_binary {
+        true => file.fill_buf()?.iter().position(|&b| b == 0),
+        false => None,
+    };
+    let mut told_binary = false;
+
+    loop {
+        let at_max = config.max_count.is_some_and(|max| num_matches >= max);
+        if at_max && after_left == 0 {
+            break;
+        }
+        text.clear();
+        let bytes = file.read_until(b'\n', &mut text)?;
+        if bytes == 0 {
+            break;
+        }
+        if check_binary && nul.is_none() {
+            nul = text.iter().position(|&b| b == 0).map(|i| offset + i);
+        }
+        let binary = nul.is_some();
+        if binary && config.binary == BinaryFiles::WithoutMatch {
+            return Ok(0);
+        }
+        line_num += 1;
+        let (num, start) = (line_num, offset);
+        let line = move |text: &[u8]| Line {
+            num,
+            offset: start,
+            text: text.to_vec(),
+        };
+        offset += bytes;
+
+        let body = text.strip_suffix(b"\n").unwrap_or(&text);
+        let is_match = !at_max && config.matcher.is_match(body)? != config.invert_match;
+        if binary {
+            // Binary files report only that they matched, but keep counting.
+            // A NUL found late still gets its notice after the earlier lines.
+            if is_match {
+                num_matches += 1;
+                if !told_binary {
+                    told_binary = true;
+                    if !sink.event(Event::Binary(nul.unwrap()))? {
+                        break;
+                    }
+                }
+            }
+            after_left = 0;
+        } else if is_match {
+            num_matches += 1;
+            let first = before.front().map_or(line_num, |line| line.num);
+            let gap = use_context && last_emitted > 0 && first > last_emitted + 1;
+            if gap && !sink.event(Event::Break)? {
+                break;
+            }
+            for line in before.drain(..) {
+                if !sink.event(Event::Context(line))? {
+                    return Ok(num_matches);
+                }
+            }
+            if !sink.event(Event::Match(line(&text)))? {
+                break;
+            }
+            last_emitted = line_num;
+            after_left = config.after_context;
+        } else if after_left > 0 {
+            if !sink.event(Event::Context(line(&text)))? {
+                break;
+            }
+            last_emitted = line_num;
+            after_left -= 1;
+        } else if config.before_context > 0 {
+            if before.len() == config.before_context {
+                before.pop_front();
+            }
+            before.push_back(line(&text));
+        }
+    }
+
+    Ok(num_matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_files, find_lines, fold_literal, has_uppercase, is_archive, open, paint, unified_diff,
+        BinaryFiles, Change, Colors, Config, Event, JsonPrinter, Line, Matcher, MyResult, Printer,
+        Syntax,
+    };
+    use ignore::overrides::{Override, OverrideBuilder};
+    use rand::{distributions::Alphanumeric, Rng};
+    use regex::bytes::{Regex, RegexBuilder};
+    use std::{
+        fs,
+        io::{BufReader, Cursor, Read, Write},
+        path::Path,
+    };
+
+    fn config(pattern: Regex, invert_match: bool, before: usize, after: usize) -> Config {
+        Config {
+            matcher: Matcher::Regex(pattern),
+            files: vec![],
+            recursive: false,
+            hidden: false,
+            no_ignore: false,
+            globs: Override::empty(),
+            max_depth: None,
+            threads: 1,
+            search_zip: false,
+            search_archives: false,
+            count: false,
+            invert_match,
+            line_number: false,
+            byte_offset: false,
+            with_filename: None,
+            label: "-".to_string(),
+            only_matching: false,
+            replace: None,
+            write: false,
+            dry_run: false,
+            colors: Colors::default(),
+            max_count: None,
+            files_with_matches: false,
+            files_without_match: false,
+            quiet: false,
+            json: false,
+            binary: BinaryFiles::Binary,
+            before_context: before,
+            after_context: after,
+        }
+    }
+
+    fn events(text: &[u8], config: &Config) -> Vec<Event> {
+        let mut events = vec![];
+        let num_matches = find_lines(Cursor::new(text), config, |event| {
+            events.push(event);
+            Ok(true)
+        });
+        assert!(num_matches.is_ok());
+        events
+    }
+
+    #[test]
+    fn test_find_lines() {
+        let text = b"Lorem\nIpsum\r\nDOLOR";
+
+        // The pattern _or_ should match the one line, "Lorem"
+        let re1 = Regex::new("or").unwrap();
+        let matches = find_lines(
+            Cursor::new(&text),
+            &config(re1.clone(), false, 0, 0),
+            |_: Event| Ok(true),
+        );
+        assert!(matches.is_ok());
+        assert_eq!(matches.unwrap(), 1);
+
+        // When inverted, the function should match the other two lines
+        let matches = find_lines(Cursor::new(&text), &config(re1, true, 0, 0), |_: Event| {
+            Ok(true)
+        });
+        assert!(matches.is_ok());
+        assert_eq!(matches.unwrap(), 2);
+
+        // This regex will be case-insensitive
+        let re2 = RegexBuilder::new("or")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+
+        // The two lines "Lorem" and "DOLOR" should match
+        let matches = find_lines(
+            Cursor::new(&text),
+            &config(re2.clone(), false, 0, 0),
+            |_: Event| Ok(true),
+        );
+        assert!(matches.is_ok());
+        assert_eq!(matches.unwrap(), 2);
+
+        // When inverted, the one remaining line should match
+        let matches = find_lines(Cursor::new(&text), &config(re2, true, 0, 0), |_: Event| {
+            Ok(true)
+        });
+        assert!(matches.is_ok());
+        assert_eq!(matches.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_find_lines_context() {
+        let text = b"a\nb\nX\nc\nd\ne\nf\nX\ng\nX\nh\n";
+        let re = Regex::new("X").unwrap();
+        // Every line is two bytes long
+        let line = |num: usize, val: &str| {
+            Event::Context(Line {
+                num,
+                offset: (num - 1) * 2,
+                text: format!("{}\n", val).into_bytes(),
+            })
+        };
+        let hit = |num: usize| {
+            Event::Match(Line {
+                num,
+                offset: (num - 1) * 2,
+                text: b"X\n".to_vec(),
+            })
+        };
+
+        // Without context only the matches are emitted
+        assert_eq!(
+            events(text, &config(re.clone(), false, 0, 0)),
+            vec![hit(3), hit(8), hit(10)]
+        );
+
+        // Overlapping windows are merged, and gaps produce a break
+        assert_eq!(
+            events(text, &config(re.clone(), false, 1, 1)),
+            vec![
+                line(2, "b"),
+                hit(3),
+                line(4, "c"),
+                Event::Break,
+                line(7, "f"),
+                hit(8),
+                line(9, "g"),
+                hit(10),
+                line(11, "h"),
+            ]
+        );
+
+        // Adjacent windows are not separated
+        assert_eq!(
+            events(text, &config(re.clone(), false, 2, 0)),
+            vec![
+                line(1, "a"),
+                line(2, "b"),
+                hit(3),
+                Event::Break,
+                line(6, "e"),
+                line(7, "f"),
+                hit(8),
+                line(9, "g"),
+                hit(10),
+            ]
+        );
+        assert_eq!(
+            events(text, &config(re, false, 0, 4)),
+            vec![
+                hit(3),
+                line(4, "c"),
+                line(5, "d"),
+                line(6, "e"),
+                line(7, "f"),
+                hit(8),
+                line(9, "g"),
+                hit(10),
+                line(11, "h"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_lines_stop() {
+        let text = b"a\nX\nb\nX\nX\nc\n";
+        let re = Regex::new("X").unwrap();
+
+        // The search ends as soon as the sink says so
+        let mut seen = 0;
+        let num_matches = find_lines(Cursor::new(text), &config(re.clone(), false, 0, 0), |_| {
+            seen += 1;
+            Ok(false)
+        });
+        assert_eq!(num_matches.unwrap(), 1);
+        assert_eq!(seen, 1);
+
+        // A max count still allows the trailing context
+        let mut conf = config(re, false, 0, 1);
+        conf.max_count = Some(2);
+        let events = events(text, &conf);
+        assert_eq!(events.len(), 4);
+        assert!(matches!(&events[3], Event::Context(line) if line.num == 5));
+
+        conf.max_count = Some(0);
+        assert_eq!(
+            find_lines(Cursor::new(text), &conf, |_: Event| Ok(true)).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_find_lines_binary() {
+        let re = Regex::new("abc").unwrap();
+        let mut conf = config(re, false, 1, 1);
+
+        // Binary files emit a single event, but every match is counted
+        let text = b"abc\0\nabc\nxyz\nabc\n";
+        assert_eq!(events(text, &conf), vec![Event::Binary(3)]);
+        let num_matches = find_lines(Cursor::new(text), &conf, |_: Event| Ok(true));
+        assert_eq!(num_matches.unwrap(), 3);
+
+        conf.binary = BinaryFiles::WithoutMatch;
+        let num_matches = find_lines(Cursor::new(text), &conf, |_: Event| Ok(true));
+        assert_eq!(num_matches.unwrap(), 0);
+
+        // A NUL past the first block turns the rest of the file binary
+        conf.binary = BinaryFiles::Binary;
+        let mut late = b"abc 1\n".repeat(20000);
+        let nul = late.len() + 1;
+        late.extend_from_slice(b"x\0y abc\nabc again\n");
+        let mut found = vec![];
+        let file = BufReader::new(Cursor::new(late));
+        find_lines(file, &conf, |event| {
+            found.push(event);
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(found.len(), 20001);
+        assert_eq!(found.last(), Some(&Event::Binary(nul)));
+
+        conf.binary = BinaryFiles::Text;
+        assert_eq!(events(text, &conf).len(), 4);
+
+        // Invalid UTF-8 is not binary and still matches
+        conf.binary = BinaryFiles::Binary;
+        let events = events(b"\xff abc\n", &conf);
+        assert!(matches!(&events[0], Event::Match(line) if line.text == b"\xff abc\n"));
+    }
+
+    #[test]
+    fn test_matcher() {
+        let matcher = |patterns: &[&str], syntax, insensitive, word, line| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            Matcher::new(&patterns, syntax, insensitive, word, line, true).unwrap()
+        };
+
+        let m = matcher(&["fox", "d.g"], Syntax::Regex, false, false, false);
+        assert!(matches!(m, Matcher::Regex(_)));
+        assert_eq!(m.find_iter(b"the fox and dog").unwrap(), vec![4..7, 12..15]);
+
+        // Fixed strings use Aho-Corasick and prefer the longest match
+        let patterns = ["d.g".to_string(), "th".to_string(), "THE".to_string()];
+        let m = Matcher::new(&patterns, Syntax::Fixed, true, false, false, false).unwrap();
+        assert!(matches!(m, Matcher::Literal(_)));
+        assert!(!m.is_match(b"dog").unwrap());
+        assert_eq!(m.find_iter(b"the d.g").unwrap(), vec![0..3, 4..7]);
+        assert!(!m.is_match("\u{212a}".as_bytes()).unwrap());
+
+        // Unicode case folding needs the regex engine, even for ASCII
+        let m = matcher(&["k", "é"], Syntax::Fixed, true, false, false);
+        assert!(matches!(m, Matcher::Regex(_)));
+        assert!(m.is_match("\u{212a}".as_bytes()).unwrap());
+        assert!(m.is_match("É".as_bytes()).unwrap());
+        // Regexes fold one character to one, so ß is not SS
+        let m = matcher(&["straße"], Syntax::Regex, true, false, false);
+        assert!(!m.is_match(b"STRASSE").unwrap());
+        assert!(m.is_match("STRAẞE".as_bytes()).unwrap());
+        // Needles fold fully, in both directions
+        let m = matcher(&["straße"], Syntax::Fixed, true, false, false);
+        assert_eq!(m.find_iter(b"x STRASSE").unwrap(), vec![2..9]);
+        assert!(m.is_match("Straẞe".as_bytes()).unwrap());
+        assert!(!m.is_match(b"STRASE").unwrap());
+        let m = matcher(&["STRASSE", "office"], Syntax::Fixed, true, false, false);
+        assert!(m.is_match("straße".as_bytes()).unwrap());
+        assert!(m.is_match("O\u{fb03}CE".as_bytes()).unwrap());
+        assert!(m.is_match("o\u{fb00}ice".as_bytes()).unwrap());
+        assert_eq!(fold_literal("a.ss"), r"a\.(?:ss|ß|ẞ)");
+
+        let m = matcher(&["the", "a.c"], Syntax::Fixed, false, true, false);
+        assert!(matches!(m, Matcher::Regex(_)));
+        assert_eq!(
+            m.find_iter(b"bathe the a.c abc").unwrap(),
+            vec![6..9, 10..13]
+        );
+        // Punctuation at the ends of a needle is not a word boundary
+        let m = matcher(&["-foo"], Syntax::Fixed, false, true, false);
+        assert!(!m.is_match(b"a-foo b").unwrap());
+        assert_eq!(m.find_iter(b"x -foo y").unwrap(), vec![2..6]);
+
+        let m = matcher(&["fox", "dog"], Syntax::Regex, false, false, true);
+        assert!(m.is_match(b"dog").unwrap());
+        assert!(!m.is_match(b"dog fox").unwrap());
+
+        // No patterns at all never match
+        assert!(!matcher(&[], Syntax::Regex, false, false, false)
+            .is_match(b"fox")
+            .unwrap());
+        assert!(!matcher(&[], Syntax::Regex, false, false, true)
+            .is_match(b"")
+            .unwrap());
+
+        let word = |unicode| {
+            let patterns = [r"\w+".to_string()];
+            Matcher::new(&patterns, Syntax::Regex, false, false, false, unicode).unwrap()
+        };
+        assert_eq!(word(true).find_iter("né".as_bytes()).unwrap(), vec![0..3]);
+        assert_eq!(word(false).find_iter("né".as_bytes()).unwrap(), vec![0..1]);
+
+        let err = Matcher::new(
+            &["fox".to_string(), "*".to_string()],
+            Syntax::Regex,
+            false,
+            false,
+            false,
+            true,
+        );
+        assert_eq!(err.unwrap_err().to_string(), "Invalid pattern \"*\"");
+
+        let err = Matcher::new(
+            &[r"(\w)\1".to_string()],
+            Syntax::Regex,
+            false,
+            false,
+            false,
+            true,
+        );
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            r#"Invalid pattern "(\w)\1": lookaround and backreferences need -P"#
+        );
+    }
+
+    #[test]
+    fn test_has_uppercase() {
+        assert!(has_uppercase("Fox", Syntax::Regex));
+        assert!(has_uppercase("straße Ä", Syntax::Regex));
+        assert!(!has_uppercase(r"fox\S+\W", Syntax::Regex));
+        assert!(!has_uppercase(r"\p{Lu}\PL\pN x", Syntax::Perl(1)));
+        assert!(has_uppercase(r"\p{Lu}X", Syntax::Regex));
+        // Fixed strings have no escapes
+        assert!(has_uppercase(r"\S", Syntax::Fixed));
+    }
+
+    #[test]
+    fn test_replacements() {
+        let m = Matcher::new(
+            &[r"(?P<key>\w+)=(\d+)".to_string()],
+            Syntax::Regex,
+            false,
+            false,
+            false,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            m.replacements(b"a=1, bc=22", "$2:${key}").unwrap(),
+            vec![(0..3, b"1:a".to_vec()), (5..10, b"22:bc".to_vec())]
+        );
+        // A missing group is empty and $$ is a dollar sign
+        assert_eq!(
+            m.replacements(b"x=9", "$3$$").unwrap(),
+            vec![(0..3, b"$".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_matcher_perl() {
+        let perl = |pattern: &str, limit| {
+            Matcher::new(
+                &[pattern.to_string()],
+                Syntax::Perl(limit),
+                false,
+                false,
+                false,
+                true,
+            )
+        };
+
+        #[cfg(not(feature = "pcre"))]
+        assert!(perl(r"(\w)\1", 1000)
+            .unwrap_err()
+            .to_string()
+            .contains("without the \"pcre\" feature"));
+
+        #[cfg(feature = "pcre")]
+        {
+            // Backreferences, lookaround, and text around invalid UTF-8
+            let m = perl(r"(\w)\1", 1000).unwrap();
+            assert_eq!(m.find_iter(b"bookkeeper").unwrap(), vec![1..3, 3..5, 5..7]);
+            let m = perl(r"(?<=\$)\d+(?![\d.])", 1000).unwrap();
+            assert_eq!(m.find_iter(b"$12.50 \xff$7").unwrap(), vec![9..10]);
+
+            let err = perl(r"(?<=a+)b", 1000).unwrap_err().to_string();
+            assert!(err.starts_with(r#"Invalid pattern "(?<=a+)b": "#));
+
+            // Runaway backtracking is an error, not a hang
+            let m = perl(r"^(a|aa)+(?=c)", 1000).unwrap();
+            let err = m.is_match(&[b'a'; 40]).unwrap_err().to_string();
+            assert!(err.starts_with("-P search failed"));
+        }
+    }
+
+    #[test]
+    fn test_printer() {
+        let re = Regex::new("x").unwrap();
+        let mut conf = config(re, false, 1, 0);
+        conf.line_number = true;
+        conf.byte_offset = true;
+        let line = |num: usize, offset: usize, text: &str| Line {
+            num,
+            offset,
+            text: text.as_bytes().to_vec(),
+        };
+
+        let mut printer = Printer::new(vec![], &conf, true);
+        printer
+            .event("a", Event::Context(line(1, 0, "w\n")))
+            .unwrap();
+        printer.event("a", Event::Match(line(2, 2, "x\n"))).unwrap();
+        printer.event("a", Event::Break).unwrap();
+        printer.event("a", Event::Match(line(5, 9, "x\n"))).unwrap();
+        printer.count("a", 3).unwrap();
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "a-1-0-w\na:2:2:x\n--\na:5:9:x\na:3\n"
+        );
+
+        let mut printer = Printer::new(vec![], &conf, false);
+        printer
+            .event("a", Event::Match(line(7, 40, "x\n")))
+            .unwrap();
+        printer.count("a", 1).unwrap();
+        assert_eq!(String::from_utf8(printer.out).unwrap(), "7:40:x\n1\n");
+    }
+
+    #[test]
+    fn test_json_printer() {
+        let conf = config(Regex::new("o").unwrap(), false, 0, 0);
+        let line = |num: usize, offset: usize, text: &[u8]| Line {
+            num,
+            offset,
+            text: text.to_vec(),
+        };
+
+        let mut out = vec![];
+        let mut json = JsonPrinter::new(&mut out, &conf, "a");
+        json.event(Event::Context(line(1, 0, b"x\n"))).unwrap();
+        json.event(Event::Break).unwrap();
+        json.event(Event::Match(line(3, 4, b"\xffoo\n"))).unwrap();
+        assert_eq!(json.stats.matches, 2);
+        assert_eq!(json.stats.searches_with_match, 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"type":"begin","data":{"path":{"text":"a"}}}"#,
+                "\n",
+                r#"{"type":"context","data":{"path":{"text":"a"},"lines":{"text":"x\n"},"#,
+                r#""line_number":1,"absolute_offset":0,"submatches":[]}}"#,
+                "\n",
+                r#"{"type":"match","data":{"path":{"text":"a"},"lines":{"bytes":"/29vCg=="},"#,
+                r#""line_number":3,"absolute_offset":4,"submatches":["#,
+                r#"{"match":{"text":"o"},"start":1,"end":2},"#,
+                r#"{"match":{"text":"o"},"start":2,"end":3}]}}"#,
+                "\n"
+            )
+        );
+
+        // Files without anything to show print nothing at all
+        let mut out = vec![];
+        let mut json = JsonPrinter::new(&mut out, &conf, "b");
+        json.end().unwrap();
+        assert_eq!(json.stats.searches, 1);
+        assert!(out.is_empty());
+
+        // Binary files say where their first NUL is
+        let mut out = vec![];
+        let mut json = JsonPrinter::new(&mut out, &conf, "c");
+        json.event(Event::Binary(7)).unwrap();
+        json.end().unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(r#""binary_offset":7,"#));
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let content: String = (1..=12).map(|n| format!("{}\n", n)).collect();
+        let change = |num: usize, new: &str| {
+            let offset = content.lines().take(num - 1).map(|l| l.len() + 1).sum();
+            Change {
+                line: Line {
+                    num,
+                    offset,
+                    text: format!("{}\n", num).into_bytes(),
+                },
+                new: new.as_bytes().to_vec(),
+            }
+        };
+        let diff = |changes: &[Change]| {
+            String::from_utf8(unified_diff("f", content.as_bytes(), changes).unwrap()).unwrap()
+        };
+
+        assert_eq!(diff(&[]), "");
+        assert_eq!(
+            diff(&[change(1, "one\n"), change(2, "two\n"), change(12, "")]),
+            concat!(
+                "--- f\n+++ f\n",
+                "@@ -1,5 +1,5 @@\n-1\n-2\n+one\n+two\n 3\n 4\n 5\n",
+                "@@ -9,4 +9,3 @@\n 9\n 10\n 11\n-12\n",
+            )
+        );
+        // Changes seven lines apart still share their context
+        assert_eq!(
+            diff(&[change(3, "a\nb\n"), change(10, "c")]),
+            concat!(
+                "--- f\n+++ f\n",
+                "@@ -1,12 +1,13 @@\n 1\n 2\n-3\n+a\n+b\n",
+                " 4\n 5\n 6\n 7\n 8\n 9\n-10\n+c\n\\ No newline at end of file\n",
+                " 11\n 12\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_colors() {
+        let colors = Colors::parse("");
+        assert_eq!(colors.selected_match, "01;31");
+        assert_eq!(colors.file_name, "35");
+        assert_eq!(colors.selected_line, "");
+
+        let colors = Colors::parse("mt=7:ln=1;33:sl=:fn=1:bogus=1:se=x");
+        assert_eq!(colors.selected_match, "7");
+        assert_eq!(colors.context_match, "7");
+        assert_eq!(colors.line_number, "1;33");
+        assert_eq!(colors.file_name, "1");
+        assert_eq!(colors.separator, "36");
+
+        let mut out = vec![];
+        paint(&mut out, "01;31", "fox").unwrap();
+        paint(&mut out, "", "dog").unwrap();
+        assert_eq!(out, b"\x1b[01;31m\x1b[Kfox\x1b[m\x1b[Kdog");
+    }
+
+    fn find(files: &[&str], recursive: bool) -> Vec<MyResult<String>> {
+        let mut conf = config(Regex::new("").unwrap(), false, 0, 0);
+        conf.files = files.iter().map(|f| f.to_string()).collect();
+        conf.recursive = recursive;
+        find_files(&conf).collect()
+    }
+
+    #[test]
+    fn test_find_files() {
+        // Verify that the function finds a file known to exist
+        let files = find(&["./tests/inputs/fox.txt"], false);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].as_ref().unwrap(), "./tests/inputs/fox.txt");
+
+        // The function should reject a directory without the recursive option
+        let files = find(&["./tests/inputs"], false);
+        assert_eq!(files.len(), 1);
+        if let Err(e) = &files[0] {
+            assert_eq!(e.to_string(), "./tests/inputs is a directory");
+        }
+
+        // Verify the function recurses to find four files in the directory
+        let res = find(&["./tests/inputs"], true);
+        let files: Vec<String> = res
+            .iter()
+            .map(|r| r.as_ref().unwrap().replace('\\', "/"))
+            .collect();
+        assert_eq!(files.len(), 4);
+        assert_eq!(
+            files,
             vec![
                 "./tests/inputs/bustle.txt",
                 "./tests/inputs/empty.txt",
@@ -259,8 +2792,109 @@ mod tests {
             .collect();
 
         // Verify that the function returns the bad file as an error
-        let files = find_files(&[bad], false);
+        let files = find(&[&bad], false);
         assert_eq!(files.len(), 1);
         assert!(files[0].is_err());
     }
+
+    #[test]
+    fn test_open() -> MyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let text = b"one\ntwo\n";
+        let gz = dir.path().join("log.1.gz");
+        let mut encoder = flate2::write::GzEncoder::new(vec![], Default::default());
+        encoder.write_all(text)?;
+        fs::write(&gz, encoder.finish()?)?;
+        let zst = dir.path().join("log.2.zst");
+        fs::write(&zst, zstd::encode_all(&text[..], 0)?)?;
+
+        let read = |path: &Path, decompress: bool| -> MyResult<Vec<u8>> {
+            let mut buf = vec![];
+            open(path.to_str().unwrap(), decompress)?.read_to_end(&mut buf)?;
+            Ok(buf)
+        };
+        assert_eq!(read(&gz, true)?, text);
+        assert_eq!(read(&zst, true)?, text);
+        assert_eq!(read(&gz, false)?, fs::read(&gz)?);
+
+        assert!(is_archive("build.tar.gz"));
+        assert!(is_archive("build.tzst"));
+        assert!(!is_archive("log.gz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_files_filters() -> MyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        for file in &[
+            "a.rs",
+            "b.txt",
+            ".hidden.rs",
+            "target/out.rs",
+            "notes/c.rs",
+            "notes/deep/d.rs",
+            ".git/config",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "x\n")?;
+        }
+        fs::write(root.join(".gitignore"), "target/\n")?;
+        fs::write(root.join("notes/.ignore"), "deep\n")?;
+
+        let mut conf = config(Regex::new("x").unwrap(), false, 0, 0);
+        conf.files = vec![root.display().to_string()];
+        conf.recursive = true;
+        let find = |conf: &Config| -> Vec<String> {
+            find_files(conf)
+                .map(|file| {
+                    let file = file.unwrap();
+                    let file = Path::new(&file).strip_prefix(root).unwrap();
+                    file.display().to_string().replace('\\', "/")
+                })
+                .collect()
+        };
+
+        assert_eq!(find(&conf), vec!["a.rs", "b.txt", "notes/c.rs"]);
+
+        conf.hidden = true;
+        assert_eq!(
+            find(&conf),
+            vec![
+                ".gitignore",
+                ".hidden.rs",
+                "a.rs",
+                "b.txt",
+                "notes/.ignore",
+                "notes/c.rs"
+            ]
+        );
+
+        conf.hidden = false;
+        conf.no_ignore = true;
+        assert_eq!(
+            find(&conf),
+            vec![
+                "a.rs",
+                "b.txt",
+                "notes/c.rs",
+                "notes/deep/d.rs",
+                "target/out.rs"
+            ]
+        );
+
+        conf.max_depth = Some(2);
+        assert_eq!(
+            find(&conf),
+            vec!["a.rs", "b.txt", "notes/c.rs", "target/out.rs"]
+        );
+
+        let mut globs = OverrideBuilder::new(".");
+        globs.add("*.rs")?;
+        globs.add("!notes")?;
+        conf.globs = globs.build()?;
+        assert_eq!(find(&conf), vec!["a.rs", "target/out.rs"]);
+        Ok(())
+    }
 }
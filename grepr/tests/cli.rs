@@ -1,7 +1,17 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
-use std::{fs, path::Path};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 use sys_info::os_type;
 
 type TestResult = Result<(), Box<dyn std::error::Error>>;
@@ -44,7 +54,7 @@ fn dies_bad_pattern() -> TestResult {
     Command::cargo_bin(PRG)?
         .args(&["*foo", FOX])
         .assert()
-        .failure()
+        .code(2)
         .stderr(predicate::str::contains("Invalid pattern \"*foo\""));
     Ok(())
 }
@@ -273,3 +283,883 @@ fn stdin_insensitive_count() -> TestResult {
         .stdout(expected);
     Ok(())
 }
+
+// --------------------------------------------------
+#[test]
+fn context() -> TestResult {
+    run(
+        &["-n", "-C", "1", "The", BUSTLE],
+        "tests/expected/bustle.txt.The.C1.n",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn after_context_multiple() -> TestResult {
+    run(
+        &["-A", "1", "-n", "the", BUSTLE, NOBODY],
+        "tests/expected/the.A1.n.multiple",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn before_context_invert() -> TestResult {
+    run(
+        &["-B", "2", "-v", "e", NOBODY],
+        "tests/expected/nobody.txt.B2.invert",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_context() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-C", "x", "foo", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid context length \"x\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn line_number_byte_offset() -> TestResult {
+    run(
+        &["-n", "-b", "The", BUSTLE],
+        "tests/expected/bustle.txt.The.n.b",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn no_filename_multiple() -> TestResult {
+    run(
+        &["-h", "-n", "the", BUSTLE, NOBODY],
+        "tests/expected/the.h.n.multiple",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn with_filename_count() -> TestResult {
+    run(
+        &["-H", "-c", "The", BUSTLE],
+        "tests/expected/bustle.txt.The.H.count",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn stdin_label() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-H", "--label", "input", "fox"])
+        .write_stdin("The fox\n")
+        .assert()
+        .stdout("input:The fox\n");
+
+    Command::cargo_bin(PRG)?
+        .args(["fox", "-", FOX])
+        .write_stdin("The fox\n")
+        .assert()
+        .stdout(
+            "(standard input):The fox\n\
+            tests/inputs/fox.txt:The quick brown fox jumps over the lazy dog.\n",
+        );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn only_matching() -> TestResult {
+    run(
+        &["-o", "-n", "-i", "the", BUSTLE],
+        "tests/expected/bustle.txt.the.o.n.insensitive",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn only_matching_byte_offset() -> TestResult {
+    run(
+        &["-o", "-b", "-i", "nobody", NOBODY],
+        "tests/expected/nobody.txt.o.b.insensitive",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn color_always() -> TestResult {
+    run(
+        &["--color=always", "-n", "-H", "The", BUSTLE],
+        "tests/expected/bustle.txt.The.color",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn color_grep_colors() -> TestResult {
+    let expected = fs::read_to_string("tests/expected/bustle.txt.the.C1.grep_colors")?;
+    Command::cargo_bin(PRG)?
+        .args(["--color=always", "-C", "1", "the", BUSTLE])
+        .env("GREP_COLORS", "ms=04;32:sl=1:fn=34")
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn color_auto_not_tty() -> TestResult {
+    let expected = fs::read_to_string("tests/expected/bustle.txt.the.lowercase")?;
+    for color in &["--color", "--color=auto", "--color=never"] {
+        Command::cargo_bin(PRG)?
+            .args([color, "the", BUSTLE])
+            .assert()
+            .stdout(expected.clone());
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn max_count_context() -> TestResult {
+    run(
+        &["-n", "-m", "1", "-A", "3", "The", BUSTLE],
+        "tests/expected/bustle.txt.The.m1.A3",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn max_count_count() -> TestResult {
+    run(
+        &["-c", "-m", "2", "-i", "the", BUSTLE, NOBODY],
+        "tests/expected/the.m2.insensitive.count",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn files_with_matches() -> TestResult {
+    run(
+        &["-l", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/the.files_with_matches",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn quiet() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-q", "-n", "the", BUSTLE, FOX])
+        .assert()
+        .stdout("");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_max_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-m", "x", "foo", FOX])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid max count \"x\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn files_without_match() -> TestResult {
+    run(
+        &["-L", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/the.files_without_match",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn exit_status() -> TestResult {
+    Command::cargo_bin(PRG)?.args(["fox", FOX]).assert().code(0);
+    Command::cargo_bin(PRG)?
+        .args(["-c", "zebra", FOX])
+        .assert()
+        .code(1)
+        .stdout("0\n");
+    Command::cargo_bin(PRG)?
+        .args(["fox", INPUTS_DIR, FOX])
+        .assert()
+        .code(2);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn quiet_exit_status() -> TestResult {
+    let bad = gen_bad_file();
+
+    // A match found before the error still counts as success
+    Command::cargo_bin(PRG)?
+        .args(["-q", "fox", FOX, &bad])
+        .assert()
+        .code(0)
+        .stdout("")
+        .stderr("");
+    Command::cargo_bin(PRG)?
+        .args(["-q", "zebra", FOX])
+        .assert()
+        .code(1);
+    Command::cargo_bin(PRG)?
+        .args(["-q", "zebra", FOX, &bad])
+        .assert()
+        .code(2);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn help_succeeds() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .arg("--help")
+        .assert()
+        .code(0)
+        .stdout(predicate::str::contains("USAGE"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_include_exclude() -> TestResult {
+    run(
+        &[
+            "-ri",
+            "the",
+            "--include",
+            "b*",
+            "--include",
+            "n*",
+            "--exclude",
+            "nobody*",
+            INPUTS_DIR,
+        ],
+        "tests/expected/the.recursive.include",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_threads_keep_order() -> TestResult {
+    for threads in &["1", "2", "8"] {
+        run(
+            &["-ri", "then", "-j", threads, INPUTS_DIR],
+            "tests/expected/the.recursive.insensitive",
+        )?;
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn streams_slow_input() -> TestResult {
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .arg("foo")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    stdin.write_all(b"foo\nbar\n")?;
+    stdin.flush()?;
+
+    // The match has to show up while the input is still open
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = stdout.read_line(&mut line);
+        let _ = tx.send(line);
+    });
+    let line = rx.recv_timeout(Duration::from_secs(10));
+    drop(stdin);
+    child.wait()?;
+    assert_eq!(line?, "foo\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn recursive_ignore_rules() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path();
+    for file in &["keep.txt", ".hidden.txt", "target/skip.txt", "a/b/deep.txt"] {
+        let file = path.join(file);
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(file, "fox\n")?;
+    }
+    fs::write(path.join(".gitignore"), "target/\n")?;
+
+    let search = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin(PRG)?
+            .args(["-r", "-l", "fox"])
+            .args(args)
+            .current_dir(path)
+            .output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    assert_eq!(search(&["."])?, "./a/b/deep.txt\n./keep.txt\n");
+    assert_eq!(search(&["--max-depth", "1", "."])?, "./keep.txt\n");
+    assert_eq!(
+        search(&["--hidden", "."])?,
+        "./.hidden.txt\n./a/b/deep.txt\n./keep.txt\n"
+    );
+    assert_eq!(
+        search(&["--no-ignore", "."])?,
+        "./a/b/deep.txt\n./keep.txt\n./target/skip.txt\n"
+    );
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_glob() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-r", "--include", "[", "foo", INPUTS_DIR])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Invalid --include \"[\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn binary_files() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let binary = dir.path().join("binary.dat");
+    fs::write(&binary, b"abc\0\nabc\nxyz\n")?;
+    let binary = binary.to_str().unwrap();
+
+    Command::cargo_bin(PRG)?
+        .args(["abc", binary])
+        .assert()
+        .code(0)
+        .stdout(format!("Binary file {} matches\n", binary));
+    Command::cargo_bin(PRG)?
+        .args(["-c", "abc", binary])
+        .assert()
+        .stdout("2\n");
+    Command::cargo_bin(PRG)?
+        .args(["-a", "-n", "abc", binary])
+        .assert()
+        .stdout(&b"1:abc\0\n2:abc\n"[..]);
+    Command::cargo_bin(PRG)?
+        .args(["--binary-files=text", "xyz", binary])
+        .assert()
+        .stdout("xyz\n");
+    Command::cargo_bin(PRG)?
+        .args(["-I", "-H", "fox", binary, FOX])
+        .assert()
+        .code(0)
+        .stdout("tests/inputs/fox.txt:The quick brown fox jumps over the lazy dog.\n");
+    Command::cargo_bin(PRG)?
+        .args(["--binary-files=without-match", "abc", binary])
+        .assert()
+        .code(1)
+        .stdout("");
+
+    // A NUL far into the file still gets the notice after the text matches
+    let late = dir.path().join("late.dat");
+    let mut text: String = (1..=20000).map(|n| format!("line foo {}\n", n)).collect();
+    text.push_str("x\0y foo\nfoo again\n");
+    fs::write(&late, text)?;
+    let late = late.to_str().unwrap();
+    let output = Command::cargo_bin(PRG)?.args(["foo", late]).output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.starts_with("line foo 1\n"));
+    assert!(stdout.ends_with(&format!("line foo 20000\nBinary file {} matches\n", late)));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn invalid_utf8() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-o", "f.x"])
+        .write_stdin(&b"\xff the fox\n\xfe dog\n"[..])
+        .assert()
+        .code(0)
+        .stdout("fox\n");
+    Command::cargo_bin(PRG)?
+        .arg("dog")
+        .write_stdin(&b"\xff the fox\n\xfe dog\n"[..])
+        .assert()
+        .stdout(&b"\xfe dog\n"[..]);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn multiple_patterns() -> TestResult {
+    run(
+        &["-e", "The", "-e", "you", BUSTLE, NOBODY],
+        "tests/expected/the.you.multiple",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn word_regexp() -> TestResult {
+    run(
+        &["-w", "-i", "the", BUSTLE, NOBODY],
+        "tests/expected/the.word.insensitive",
+    )
+}
+
+// --------------------------------------------------
+#[test]
+fn word_regexp_punctuation() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-w", "-F", "--", "-foo"])
+        .write_stdin("a-foo b\nx -foo y\n")
+        .assert()
+        .code(0)
+        .stdout("x -foo y\n");
+    Command::cargo_bin(PRG)?
+        .args(["-w", "-F", "foo-"])
+        .write_stdin("foo-bar\n")
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn line_regexp() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-x", "-n", "Until eternity.|And", BUSTLE])
+        .assert()
+        .stdout("9:Until eternity.\n");
+    Command::cargo_bin(PRG)?
+        .args(["-x", "-F", "Until eternity", BUSTLE])
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn pattern_file_fixed_strings() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let patterns = dir.path().join("patterns.txt");
+    fs::write(&patterns, "nobody!\nFROG\n")?;
+    let patterns = patterns.to_str().unwrap();
+
+    Command::cargo_bin(PRG)?
+        .args(["-F", "-i", "-f", patterns, NOBODY])
+        .assert()
+        .stdout("I'm Nobody! Who are you?\r\nHow public—like a Frog—\r\n");
+
+    // The pattern "!" is not special as a fixed string
+    Command::cargo_bin(PRG)?
+        .args(["-c", "-F", "-e", "y!", "-f", patterns, NOBODY, FOX])
+        .assert()
+        .stdout("tests/inputs/nobody.txt:2\ntests/inputs/fox.txt:0\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(feature = "pcre")]
+fn perl_regexp() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-P", "-n", "-o", r"(\w)\1", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout("6:ee\n7:tt\n8:ll\n");
+    // Each pattern's backreferences count its own groups
+    Command::cargo_bin(PRG)?
+        .args(["-P", "-o", "-e", r"(a)\1", "-e", r"(b)\1"])
+        .write_stdin("aa\nbb\nab\nxbbaay\n")
+        .assert()
+        .stdout("aa\nbb\nbb\naa\n");
+    Command::cargo_bin(PRG)?
+        .args(["-P", "-n", r"(?<=The )(?!bustle)\w+", BUSTLE])
+        .assert()
+        .stdout("2:The morning after death\n6:The sweeping up the heart,\n");
+    Command::cargo_bin(PRG)?
+        .args(["-P", "-w", "--", "-fo+"])
+        .write_stdin("a-foo b\nx -foo y\n")
+        .assert()
+        .stdout("x -foo y\n");
+    Command::cargo_bin(PRG)?
+        .args(["-P", "--backtrack-limit", "100", r"^(a|aa)+(?=c)", "-"])
+        .write_stdin(format!("{}\n", "a".repeat(40)))
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("-P search failed"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+#[cfg(not(feature = "pcre"))]
+fn perl_regexp_unsupported() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-P", r"(\w)\1", BUSTLE])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "built without the \"pcre\" feature",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_perl_only_pattern() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([r"foo(?=bar)", BUSTLE])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "lookaround and backreferences need -P",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn search_zip() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let gz = dir.path().join("app.log.1.gz");
+    let mut encoder = flate2::write::GzEncoder::new(vec![], Default::default());
+    encoder.write_all(b"start\nerror: disk full\nstop\n")?;
+    fs::write(&gz, encoder.finish()?)?;
+    let zst = dir.path().join("app.log.2.zst");
+    fs::write(&zst, zstd::encode_all(&b"error: timeout\n"[..], 0)?)?;
+    let (gz, zst) = (gz.to_str().unwrap(), zst.to_str().unwrap());
+
+    Command::cargo_bin(PRG)?
+        .args(["-z", "-n", "error", gz, zst])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "{}:2:error: disk full\n{}:1:error: timeout\n",
+            gz, zst
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["error", gz])
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn search_archives() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(vec![], Default::default()));
+    for (path, text) in [
+        ("build/a.txt", "fox\ndog\n"),
+        ("build/b.txt", "cat\n"),
+        ("build/c.txt", "the fox\n"),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, text.as_bytes())?;
+    }
+    let archive = dir.path().join("build.tar.gz");
+    fs::write(&archive, builder.into_inner()?.finish()?)?;
+    let archive = archive.to_str().unwrap();
+
+    Command::cargo_bin(PRG)?
+        .args(["--search-archives", "-n", "fox", archive])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "{0}!build/a.txt:1:fox\n{0}!build/c.txt:1:the fox\n",
+            archive
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["--search-archives", "-L", "fox", archive])
+        .assert()
+        .stdout(format!("{}!build/b.txt\n", archive));
+    Command::cargo_bin(PRG)?
+        .args(["--search-archives", "-h", "-A1", "fox", archive])
+        .assert()
+        .stdout("fox\ndog\n--\nthe fox\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn json_output() -> TestResult {
+    let output = Command::cargo_bin(PRG)?
+        .args(["--json", "-B1", "death", BUSTLE, EMPTY])
+        .output()?;
+    assert_eq!(output.status.code(), Some(0));
+    let messages = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    let types: Vec<_> = messages.iter().map(|m| m["type"].as_str()).collect();
+    assert_eq!(
+        types,
+        [
+            Some("begin"),
+            Some("context"),
+            Some("match"),
+            Some("end"),
+            Some("summary")
+        ]
+    );
+
+    let data = &messages[2]["data"];
+    assert_eq!(data["path"]["text"], BUSTLE);
+    assert_eq!(data["lines"]["text"], "The morning after death\n");
+    assert_eq!(data["line_number"], 2);
+    assert_eq!(data["absolute_offset"], 22);
+    assert_eq!(data["submatches"][0]["match"]["text"], "death");
+    assert_eq!(data["submatches"][0]["start"], 18);
+    assert_eq!(data["submatches"][0]["end"], 23);
+
+    let stats = &messages[3]["data"]["stats"];
+    assert_eq!(stats["matched_lines"], 1);
+    assert_eq!(stats["bytes_searched"], fs::metadata(BUSTLE)?.len());
+    assert!(stats["elapsed"]["human"].is_string());
+
+    let stats = &messages[4]["data"]["stats"];
+    assert_eq!(stats["searches"], 2);
+    assert_eq!(stats["searches_with_match"], 1);
+    assert_eq!(stats["matches"], 1);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_json_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--json", "-c", "fox", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_preview() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-n", "--replace", "$2 $1", r"(\w+) (in|after)\b", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout("1:The in bustle a house\n2:The after morning death\n");
+    Command::cargo_bin(PRG)?
+        .args(["-o", "-F", "--replace", "[$0]", "the", BUSTLE])
+        .assert()
+        .stdout("[the]\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_write() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("main.rs");
+    fs::write(&file, "let a = foo(1);\nlet b = foo(22);\n")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640))?;
+    }
+    let file = file.to_str().unwrap();
+
+    Command::cargo_bin(PRG)?
+        .args(["--dry-run", "--replace", "bar($1)", r"foo\((\d+)\)", file])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "--- {0}\n+++ {0}\n@@ -1,2 +1,2 @@\n{1}",
+            file, "-let a = foo(1);\n-let b = foo(22);\n+let a = bar(1);\n+let b = bar(22);\n"
+        ));
+    assert_eq!(
+        fs::read_to_string(file)?,
+        "let a = foo(1);\nlet b = foo(22);\n"
+    );
+
+    Command::cargo_bin(PRG)?
+        .args([
+            "--write",
+            "-m1",
+            "--replace",
+            "bar($1)",
+            r"foo\((\d+)\)",
+            file,
+        ])
+        .assert()
+        .code(0)
+        .stdout("let a = bar(1);\n");
+    assert_eq!(
+        fs::read_to_string(file)?,
+        "let a = bar(1);\nlet b = foo(22);\n"
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(file)?.permissions().mode() & 0o777, 0o640);
+    }
+    assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--replace", "x", "foo"])
+        .write_stdin("foo\n")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Can't write to standard input"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn replace_multiple_patterns() -> TestResult {
+    // Each pattern numbers and names its groups on its own
+    Command::cargo_bin(PRG)?
+        .args(["-e", r"foo(\d)", "-e", r"bar(\d)", "--replace", "X$1"])
+        .write_stdin("foo1\nbar2\n")
+        .assert()
+        .code(0)
+        .stdout("X1\nX2\n");
+    Command::cargo_bin(PRG)?
+        .args([
+            "-e",
+            r"foo(?P<n>\d)",
+            "-e",
+            r"bar(?P<n>\d)",
+            "--replace",
+            "<$n>",
+        ])
+        .write_stdin("foo1 bar2\n")
+        .assert()
+        .code(0)
+        .stdout("<1> <2>\n");
+
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("calls.txt");
+    fs::write(&file, "get(1) put(2)\nput(3)\n")?;
+    let file = file.to_str().unwrap();
+    Command::cargo_bin(PRG)?
+        .args([
+            "--write",
+            "-e",
+            r"get\((\d)\)",
+            "-e",
+            r"put\((\d)\)",
+            "--replace",
+            "call($1)",
+            file,
+        ])
+        .assert()
+        .code(0);
+    assert_eq!(fs::read_to_string(file)?, "call(1) call(2)\ncall(3)\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_write_dry_run() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "--replace", "x", "fox", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn smart_case() -> TestResult {
+    let search = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin(PRG)?
+            .args(args)
+            .write_stdin("The\nthe\nΣΑΣ\nσας\n")
+            .output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    assert_eq!(search(&["-S", "the"])?, "The\nthe\n");
+    assert_eq!(search(&["-S", "The"])?, "The\n");
+    assert_eq!(search(&["-S", r"\Ahe"])?, "");
+    assert_eq!(search(&["-i", "-S", "The"])?, "The\n");
+    assert_eq!(search(&["-S", "-i", "The"])?, "The\nthe\n");
+    assert_eq!(search(&["-S", "σας"])?, "ΣΑΣ\nσας\n");
+    assert_eq!(search(&["-i", "--no-unicode", "σας"])?, "σας\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn full_case_folding() -> TestResult {
+    let search = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = Command::cargo_bin(PRG)?
+            .args(args)
+            .write_stdin("Straße\nSTRASSE\nStrase\n")
+            .output()?;
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    assert_eq!(search(&["-Fi", "straße"])?, "Straße\nSTRASSE\n");
+    assert_eq!(search(&["-F", "-S", "strasse"])?, "Straße\nSTRASSE\n");
+    assert_eq!(search(&["-Fio", "ss"])?, "ß\nSS\n");
+    assert_eq!(search(&["-Fi", "--no-unicode", "strasse"])?, "STRASSE\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn no_unicode() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-o", "--no-unicode", r"\w+"])
+        .write_stdin("naïve\n")
+        .assert()
+        .stdout("na\nve\n");
+    Command::cargo_bin(PRG)?
+        .args(["-P", "--no-unicode", "x", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn config_file_defaults() -> TestResult {
+    let dir = tempfile::tempdir()?;
+    let defaults = dir.path().join("greprrc");
+    fs::write(
+        &defaults,
+        "# team defaults\n-n\n\n--smart-case\n--max-count=1\n",
+    )?;
+
+    Command::cargo_bin(PRG)?
+        .env("GREPR_CONFIG_PATH", &defaults)
+        .args(["the", BUSTLE])
+        .assert()
+        .stdout("1:The bustle in a house\n");
+    // The command line comes last, so it wins
+    Command::cargo_bin(PRG)?
+        .env("GREPR_CONFIG_PATH", &defaults)
+        .args(["-m", "2", "the", BUSTLE])
+        .assert()
+        .stdout("1:The bustle in a house\n2:The morning after death\n");
+    Command::cargo_bin(PRG)?
+        .env("GREPR_CONFIG_PATH", &defaults)
+        .args(["--no-config", "the", BUSTLE])
+        .assert()
+        .stdout("The sweeping up the heart,\n");
+    Command::cargo_bin(PRG)?
+        .env("GREPR_CONFIG_PATH", dir.path().join("missing"))
+        .args(["the", BUSTLE])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("missing"));
+    Ok(())
+}
@@ -0,0 +1,3 @@
+1:0:The bustle in a house
+2:22:The morning after death
+6:97:The sweeping up the heart,
@@ -1,17 +1,52 @@
+use crate::OutputFormat::*;
 use clap::{App, Arg};
+use regex::Regex;
+use serde_json::json;
 use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     error::Error,
-    fs::File,
-    io::{self, BufRead, BufReader, Write},
+    f64::consts::LN_2,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    num::NonZeroUsize,
+    path::Path,
 };
+use tempfile::NamedTempFile;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Tsv,
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CountFormat {
+    prefix: String,
+    suffix: String,
+    width: usize,
+    left: bool,
+    zero: bool,
+}
+
 #[derive(Debug)]
 pub struct Config {
     in_file: String,
     out_file: Option<String>,
     count: bool,
+    zero_terminated: bool,
+    global: bool,
+    approx: Option<usize>,
+    in_place: bool,
+    count_format: CountFormat,
+    format: OutputFormat,
+    top: Option<usize>,
 }
 
 pub fn get_args() -> MyResult<Config> {
@@ -38,52 +73,337 @@ pub fn get_args() -> MyResult<Config> {
                 .long("count")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("zero_terminated")
+                .value_name("ZERO")
+                .help("Line delimiter is NUL, not newline")
+                .short("z")
+                .long("zero-terminated")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("global")
+                .value_name("GLOBAL")
+                .help("Remove all duplicates, not just adjacent ones")
+                .short("g")
+                .long("global")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("approx")
+                .value_name("APPROX")
+                .help(
+                    "Use a Bloom filter to bound memory (implies --global); \
+                     about 1% of distinct lines may be dropped as false positives",
+                )
+                .long("approx")
+                .takes_value(false)
+                .conflicts_with_all(&["count", "format", "top"]),
+        )
+        .arg(
+            Arg::with_name("capacity")
+                .value_name("LINES")
+                .help("Expected number of unique lines for --approx")
+                .long("capacity")
+                .default_value("10000000"),
+        )
+        .arg(
+            Arg::with_name("in_place")
+                .value_name("IN_PLACE")
+                .help("Edit INPUT in place")
+                .long("in-place")
+                .takes_value(false)
+                .conflicts_with("out_file"),
+        )
+        .arg(
+            Arg::with_name("count_format")
+                .value_name("FORMAT")
+                .help("printf-style format for counts, e.g. \"%7d \"")
+                .long("count-format")
+                .default_value("%4d "),
+        )
+        .arg(
+            Arg::with_name("format")
+                .value_name("FORMAT")
+                .help("Output format (tsv and json always include counts)")
+                .long("format")
+                .possible_values(&["text", "tsv", "json"]),
+        )
+        .arg(
+            Arg::with_name("top")
+                .value_name("N")
+                .help("Show only the N most frequent lines (implies --global --count)")
+                .long("top"),
+        )
         .get_matches();
 
+    let capacity = matches
+        .value_of("capacity")
+        .expect("capacity has a default value");
+    let capacity = parse_int(capacity).map_err(|e| format!("illegal capacity -- {}", e))?;
+    let approx = matches.is_present("approx").then_some(capacity);
+
+    let count_format = matches
+        .value_of("count_format")
+        .expect("count_format has a default value");
+    let count_format = parse_count_format(count_format)
+        .map_err(|e| format!("illegal count format -- \"{}\"", e))?;
+
+    let format = match matches.value_of("format") {
+        Some("tsv") => Tsv,
+        Some("json") => Json,
+        _ => Text,
+    };
+
+    let top = matches
+        .value_of("top")
+        .map(parse_int)
+        .transpose()
+        .map_err(|e| format!("illegal top count -- {}", e))?;
+
+    let in_file = matches.value_of("in_file").map(str::to_string).unwrap();
+    let in_place = matches.is_present("in_place");
+    if in_place && in_file == "-" {
+        return Err(From::from("--in-place requires an input file"));
+    }
+
     Ok(Config {
-        in_file: matches.value_of("in_file").map(str::to_string).unwrap(),
+        in_file,
         out_file: matches.value_of("out_file").map(String::from),
-        count: matches.is_present("count"),
+        count: matches.is_present("count") || format != Text || top.is_some(),
+        zero_terminated: matches.is_present("zero_terminated"),
+        global: matches.is_present("global") || approx.is_some() || top.is_some(),
+        approx,
+        in_place,
+        count_format,
+        format,
+        top,
     })
 }
 
 pub fn run(config: Config) -> MyResult<()> {
-    let mut file = open(&config.in_file).map_err(|e| format!("{}: {}", config.in_file, e))?;
-    let mut out_file: Box<dyn Write> = match &config.out_file {
-        Some(out_name) => Box::new(File::create(&out_name)?),
-        _ => Box::new(io::stdout()),
-    };
-    let mut print = |count: &u64, line: &String| -> MyResult<()> {
-        if count > &0 {
-            if config.count {
-                write!(out_file, "{:>4} {}", &count, &line)?;
-            } else {
-                write!(out_file, "{}", &line)?;
+    let file = open(&config.in_file).map_err(|e| format!("{}: {}", config.in_file, e))?;
+    if config.in_place {
+        // Write to a temp file beside the input so the rename stays atomic
+        let path = fs::canonicalize(&config.in_file)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut out_file = BufWriter::new(NamedTempFile::new_in(dir)?);
+        uniq(file, &mut out_file, &config)?;
+        let tmp = out_file.into_inner().map_err(|e| e.into_error())?;
+        tmp.as_file()
+            .set_permissions(fs::metadata(&path)?.permissions())?;
+        tmp.persist(&path)?;
+        return Ok(());
+    }
+
+    match &config.out_file {
+        Some(out_name) => {
+            if is_same_file(&config.in_file, out_name) {
+                return Err(From::from(format!(
+                    "{}: input and output are the same file (use --in-place)",
+                    out_name
+                )));
+            }
+            let mut out_file = BufWriter::new(File::create(out_name)?);
+            uniq(file, &mut out_file, &config)?;
+            out_file.flush()?;
+            Ok(())
+        }
+        _ => uniq(file, &mut io::stdout(), &config),
+    }
+}
+
+fn uniq(mut file: impl BufRead, out_file: &mut impl Write, config: &Config) -> MyResult<()> {
+    let delimiter = if config.zero_terminated { b'\0' } else { b'\n' };
+    let mut print = |count: u64, line: &[u8]| -> MyResult<()> {
+        if count > 0 {
+            match config.format {
+                Text => {
+                    if config.count {
+                        write!(out_file, "{}", config.count_format.format(count))?;
+                    }
+                    out_file.write_all(line)?;
+                }
+                Tsv => {
+                    write!(out_file, "{}\t", count)?;
+                    out_file.write_all(trim_delimiter(line, delimiter))?;
+                    writeln!(out_file)?;
+                }
+                Json => {
+                    let line = String::from_utf8_lossy(trim_delimiter(line, delimiter));
+                    writeln!(out_file, "{}", json!({ "count": count, "line": line }))?;
+                }
             }
         };
         Ok(())
     };
-    let mut line = String::new();
-    let mut last = String::new();
-    let mut count: u64 = 0;
-    loop {
-        let bytes = file.read_line(&mut line)?;
-        if bytes == 0 {
-            break;
+    let mut line = Vec::new();
+
+    if let Some(capacity) = config.approx {
+        let mut seen = BloomFilter::new(capacity, FALSE_POSITIVE_RATE)?;
+        while file.read_until(delimiter, &mut line)? > 0 {
+            if seen.insert(trim_delimiter(&line, delimiter)) {
+                print(1, &line)?;
+            }
+            line.clear();
         }
-        if line.trim_end() != last.trim_end() {
-            print(&count, &last)?;
-            last = line.clone();
-            count = 0;
+    } else if config.global && !config.count {
+        let mut seen = HashSet::new();
+        while file.read_until(delimiter, &mut line)? > 0 {
+            if !seen.contains(trim_delimiter(&line, delimiter)) {
+                seen.insert(trim_delimiter(&line, delimiter).to_vec());
+                print(1, &line)?;
+            }
+            line.clear();
+        }
+    } else if config.global {
+        let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut groups: Vec<(u64, Vec<u8>)> = vec![];
+        while file.read_until(delimiter, &mut line)? > 0 {
+            match index.get(trim_delimiter(&line, delimiter)) {
+                Some(&i) => groups[i].0 += 1,
+                None => {
+                    index.insert(trim_delimiter(&line, delimiter).to_vec(), groups.len());
+                    groups.push((1, line.clone()));
+                }
+            }
+            line.clear();
+        }
+        if let Some(num) = config.top {
+            groups.sort_by_key(|(count, _)| Reverse(*count));
+            groups.truncate(num);
+            // The final line may have lacked a terminator before sorting
+            for (_, line) in groups.iter_mut() {
+                if !line.ends_with(&[delimiter]) {
+                    line.push(delimiter);
+                }
+            }
+        }
+        for (count, line) in &groups {
+            print(*count, line)?;
+        }
+    } else {
+        let mut last = Vec::new();
+        let mut count: u64 = 0;
+        loop {
+            let bytes = file.read_until(delimiter, &mut line)?;
+            if bytes == 0 {
+                break;
+            }
+            if trim_delimiter(&line, delimiter) != trim_delimiter(&last, delimiter) {
+                print(count, &last)?;
+                last = line.clone();
+                count = 0;
+            }
+            count += 1;
+            line.clear();
         }
-        count += 1;
-        line.clear();
-    }
 
-    print(&count, &last)?;
+        print(count, &last)?;
+    }
     Ok(())
 }
 
+#[cfg(unix)]
+fn is_same_file(in_file: &str, out_file: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(in_file), fs::metadata(out_file)) {
+        (Ok(a), Ok(b)) => in_file != "-" && a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(in_file: &str, out_file: &str) -> bool {
+    match (fs::canonicalize(in_file), fs::canonicalize(out_file)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn trim_delimiter(line: &[u8], delimiter: u8) -> &[u8] {
+    line.strip_suffix(&[delimiter]).unwrap_or(line)
+}
+
+fn parse_int(val: &str) -> MyResult<usize> {
+    match val.trim().parse::<NonZeroUsize>() {
+        Ok(n) => Ok(usize::from(n)),
+        Err(_) => Err(From::from(val)),
+    }
+}
+
+fn parse_count_format(val: &str) -> Result<CountFormat, String> {
+    let spec_re = Regex::new(r"^([^%]*)%(-)?(0)?(\d*)d([^%]*)$").unwrap();
+    let caps = spec_re.captures(val).ok_or_else(|| val.to_string())?;
+    Ok(CountFormat {
+        prefix: caps[1].to_string(),
+        suffix: caps[5].to_string(),
+        width: caps[4].parse().unwrap_or(0),
+        left: caps.get(2).is_some(),
+        zero: caps.get(3).is_some(),
+    })
+}
+
+impl CountFormat {
+    fn format(&self, count: u64) -> String {
+        let width = self.width;
+        let count = if self.left {
+            format!("{:<width$}", count, width = width)
+        } else if self.zero {
+            format!("{:0>width$}", count, width = width)
+        } else {
+            format!("{:>width$}", count, width = width)
+        };
+        format!("{}{}{}", self.prefix, count, self.suffix)
+    }
+}
+
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u64,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, false_positive_rate: f64) -> MyResult<Self> {
+        let lines = capacity as f64;
+        let num_bits = (-lines * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = (num_bits as f64 / lines * LN_2).round().max(1.0) as u64;
+        let words = num_bits.div_ceil(64) as usize;
+        let mut bits = Vec::new();
+        bits.try_reserve_exact(words)
+            .map_err(|_| format!("illegal capacity -- {} (too large)", capacity))?;
+        bits.resize(words, 0);
+        Ok(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+
+    // Returns true if the value was (definitely) not seen before.
+    fn insert(&mut self, value: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let h1 = hasher.finish();
+        hasher.write_u8(0xff);
+        let h2 = hasher.finish() | 1;
+
+        let mut inserted = false;
+        for i in 0..self.num_hashes {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                inserted = true;
+            }
+        }
+        inserted
+    }
+}
+
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
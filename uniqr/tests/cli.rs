@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::Command;
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
@@ -605,3 +607,299 @@ fn t6_outfile_count() -> TestResult {
 fn t6_stdin_outfile_count() -> TestResult {
     run_stdin_outfile_count(&T6)
 }
+
+// --------------------------------------------------
+#[test]
+fn keeps_trailing_whitespace() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .arg("-c")
+        .write_stdin("a \na\na\n")
+        .assert()
+        .success()
+        .stdout("   1 a \n   2 a\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn zero_terminated() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .arg("-z")
+        .write_stdin("a\0a\0b\nb\0b\nb")
+        .assert()
+        .success()
+        .stdout("a\0b\nb\0");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn zero_terminated_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--zero-terminated", "--count"])
+        .write_stdin("a\0a\0b\0")
+        .assert()
+        .success()
+        .stdout("   2 a\0   1 b\0");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn invalid_utf8() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .write_stdin(&b"\xff\n\xff\n\xfe\n"[..])
+        .assert()
+        .success()
+        .stdout(&b"\xff\n\xfe\n"[..]);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn global() -> TestResult {
+    let input = fs::read_to_string(THREE.input)?;
+    Command::cargo_bin(PRG)?
+        .arg("--global")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("a\nb\nc\nd\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn global_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "-g", "-c"])
+        .assert()
+        .success()
+        .stdout("   4 a\n   2 b\n   3 c\n   4 d\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn approx() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--approx", "--capacity", "100"])
+        .assert()
+        .success()
+        .stdout("a\nb\nc\nd\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_approx_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--approx", "-c"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_capacity() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--approx", "--capacity", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("illegal capacity -- 0"));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_huge_capacity() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--approx", "--capacity", "100000000000000000"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "illegal capacity -- 100000000000000000 (too large)",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_same_file() -> TestResult {
+    let file = NamedTempFile::new()?;
+    fs::copy(THREE.input, file.path())?;
+    let path = file.path().to_str().unwrap();
+    Command::cargo_bin(PRG)?
+        .args([path, path])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "input and output are the same file",
+        ));
+
+    let contents = fs::read_to_string(path)?;
+    assert_eq!(fs::read_to_string(THREE.input)?, contents);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn in_place() -> TestResult {
+    let file = NamedTempFile::new()?;
+    fs::copy(THREE.input, file.path())?;
+    let path = file.path().to_str().unwrap();
+    Command::cargo_bin(PRG)?
+        .args([path, "--in-place", "-c"])
+        .assert()
+        .success()
+        .stdout("");
+
+    let contents = fs::read_to_string(path)?;
+    assert_eq!(fs::read_to_string(THREE.out_count)?, contents);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[cfg(unix)]
+#[test]
+fn in_place_keeps_permissions() -> TestResult {
+    use std::os::unix::fs::PermissionsExt;
+
+    let file = NamedTempFile::new()?;
+    fs::copy(THREE.input, file.path())?;
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(0o640))?;
+    let path = file.path().to_str().unwrap();
+    // GNU uniq uses -i for --ignore-case, so it must never rewrite a file
+    Command::cargo_bin(PRG)?
+        .args(["-i", path])
+        .assert()
+        .failure();
+    assert_eq!(fs::read_to_string(THREE.input)?, fs::read_to_string(path)?);
+
+    Command::cargo_bin(PRG)?
+        .args(["--in-place", path])
+        .assert()
+        .success();
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    assert_eq!(fs::read_to_string(THREE.out)?, fs::read_to_string(path)?);
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_in_place_stdin() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .arg("--in-place")
+        .write_stdin("a\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--in-place requires an input file",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_in_place_outfile() -> TestResult {
+    let outfile = NamedTempFile::new()?;
+    let outpath = outfile.path().to_str().unwrap();
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, outpath, "--in-place"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn count_format() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([T4.input, "-c", "--count-format", "%-3d|"])
+        .assert()
+        .success()
+        .stdout("2  |a\n1  |b");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn count_format_zero_padded() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([T4.input, "-c", "--count-format", "[%06d] "])
+        .assert()
+        .success()
+        .stdout("[000002] a\n[000001] b");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_count_format() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([T4.input, "-c", "--count-format", "%s"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("illegal count format -- \"%s\""));
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn format_tsv() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([T4.input, "--format", "tsv"])
+        .assert()
+        .success()
+        .stdout("2\ta\n1\tb\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn format_json() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--format", "json"])
+        .write_stdin("a\"\na\"\n\\b\n")
+        .assert()
+        .success()
+        .stdout("{\"count\":2,\"line\":\"a\\\"\"}\n{\"count\":1,\"line\":\"\\\\b\"}\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn top() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--top", "2"])
+        .write_stdin("b\na\nc\na\nc\nc\nb")
+        .assert()
+        .success()
+        .stdout("   3 c\n   2 b\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn top_json() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--top", "1", "--format", "json"])
+        .assert()
+        .success()
+        .stdout("{\"count\":4,\"line\":\"a\"}\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+#[test]
+fn dies_bad_top() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([THREE.input, "--top", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("illegal top count -- 0"));
+    Ok(())
+}